@@ -2,21 +2,48 @@ extern crate wayland_scanner;
 
 use std::env::var;
 use std::path::Path;
+use std::process::Command;
 
 use wayland_scanner::{Side, generate_code, generate_interfaces};
 
 fn main() {
-    // Location of the xml file, relative to the `Cargo.toml`
-    let protocol_file = "./desktop-shell.xml";
+    // Location of the xml files, relative to the `Cargo.toml`, and the name of the generated files
+    let protocols = [("./desktop-shell.xml", "desktop_shell"),
+                     ("./xdg-shell.xml", "xdg_shell"),
+                     ("./xdg-output-unstable-v1.xml", "xdg_output"),
+                     ("./wlr-layer-shell-unstable-v1.xml", "layer_shell")];
 
     // Target directory for the generate files
     let out_dir_str = var("OUT_DIR").unwrap();
     let out_dir = Path::new(&out_dir_str);
 
-    generate_code(protocol_file,
-                  out_dir.join("desktop_shell.rs"),
-                  Side::Client /* Replace by `Side::Server` for server-side code */);
+    for &(protocol_file, name) in &protocols {
+        generate_code(protocol_file,
+                      out_dir.join(format!("{}.rs", name)),
+                      Side::Client /* Replace by `Side::Server` for server-side code */);
 
-    // interfaces are the same for client and server
-    generate_interfaces(protocol_file, out_dir.join("desktop_shell_interfaces.rs"));
+        // interfaces are the same for client and server
+        generate_interfaces(protocol_file, out_dir.join(format!("{}_interfaces.rs", name)));
+    }
+
+    find_harfbuzz();
+}
+
+// HarfBuzz is linked for text shaping, pkg-config is used to locate it where available
+// Without pkg-config the library has to be in the default search path of the linker
+fn find_harfbuzz() {
+    let output = match Command::new("pkg-config").args(&["--libs-only-L", "harfbuzz"]).output() {
+        Ok(output) => output,
+        Err(_) => return,
+    };
+
+    if !output.status.success() {
+        panic!("HarfBuzz not found, please install it with its development files \
+                (`libharfbuzz-dev` or `harfbuzz`).");
+    }
+
+    let libs = String::from_utf8_lossy(&output.stdout);
+    for dir in libs.split_whitespace().filter(|lib| lib.starts_with("-L")) {
+        println!("cargo:rustc-link-search=native={}", &dir[2..]);
+    }
 }
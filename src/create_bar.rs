@@ -1,41 +1,68 @@
-use std::fs;
-use tempfile;
+use toml;
 use std::error;
-use std::sync::mpsc;
+use std::collections;
+use std::sync::{self, mpsc};
 use std::{thread, cmp};
-use std::io::{self, Write};
 use image::{self, GenericImage, Pixel};
 
 use modules::Block;
 use mouse::MouseEvent;
+use output::{OutputEvent, BarImage};
 use parse_input::{self, Config};
 
-// TODO: Look for actual changes in modules before requesting redraw!
-pub fn start_bar_creator(bar_img_out: mpsc::Sender<(fs::File, i32)>,
-                         resize_in: mpsc::Receiver<u32>,
-                         mouse_in: mpsc::Receiver<MouseEvent>)
-                         -> Result<(), Box<error::Error>> {
-    let mut output_width = 0;
-    let mut bg_img = image::DynamicImage::new_rgba8(0, 0);
-    let (combined_out, combined_in) = mpsc::channel();
+struct Bar {
+    config: Config,
+    // Output names the config has been read with
+    names: Vec<String>,
+    // Width of the image in buffer pixels
+    width: u32,
+    bg_img: image::DynamicImage,
+    alive: sync::Arc<sync::atomic::AtomicBool>,
+    // The bar has changed since the last image has been sent
+    dirty: bool,
+    // The panel has not displayed the last image yet
+    frame_pending: bool,
+    // Last image sent to the panel, used for finding the regions which changed
+    last_bytes: Vec<u8>,
+    // Position of every block in the last image
+    layout: Vec<BlockRect>,
+}
 
-    let mut config = parse_input::read_config()?;
+// Horizontal area of a block inside the bar, in buffer pixels
+#[derive(Clone, Debug, PartialEq)]
+struct BlockRect {
+    x: u32,
+    width: u32,
+}
 
-    // Start interval notification callback for every block
-    // This will spawn threads inside the start_interval methods
-    for element in config.left_blocks
-        .iter_mut()
-        .chain(config.center_blocks.iter_mut())
-        .chain(config.right_blocks.iter_mut()) {
-        element.start_interval(combined_out.clone());
-    }
+// Events of a single bar, tagged with the output id in the combined channel
+enum BarEvent {
+    Output(OutputEvent),
+    Mouse(MouseEvent),
+    // A block wants to be rendered again
+    Interval,
+    // The panel is ready for the next image
+    Frame,
+}
+
+// Every output gets its own bar, identified by the id sent by the panel
+// A new image is only rendered once the panel has displayed the previous one
+pub fn start_bar_creator(config_val: toml::Value,
+                         bar_img_out: mpsc::Sender<BarImage>,
+                         resize_in: mpsc::Receiver<OutputEvent>,
+                         mouse_in: mpsc::Receiver<(u32, MouseEvent)>,
+                         frame_in: mpsc::Receiver<u32>)
+                         -> Result<(), Box<error::Error>> {
+    let mut bars: collections::HashMap<u32, Bar> = collections::HashMap::new();
+    let (combined_out, combined_in) = mpsc::channel();
 
     // Combine interval with output_width
     {
         let combined_out = combined_out.clone();
         thread::spawn(move || {
-            while let Ok(output_width) = resize_in.recv() {
-                if combined_out.send((Some(output_width), None)).is_err() {
+            while let Ok(output_event) = resize_in.recv() {
+                let output = output_event.id;
+                if combined_out.send((output, BarEvent::Output(output_event))).is_err() {
                     break;
                 }
             }
@@ -46,8 +73,20 @@ pub fn start_bar_creator(bar_img_out: mpsc::Sender<(fs::File, i32)>,
     {
         let combined_out = combined_out.clone();
         thread::spawn(move || {
-            while let Ok(event) = mouse_in.recv() {
-                if combined_out.send((None, Some(event))).is_err() {
+            while let Ok((output, event)) = mouse_in.recv() {
+                if combined_out.send((output, BarEvent::Mouse(event))).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Add frame notifications to combined channel
+    {
+        let combined_out = combined_out.clone();
+        thread::spawn(move || {
+            while let Ok(output) = frame_in.recv() {
+                if combined_out.send((output, BarEvent::Frame)).is_err() {
                     break;
                 }
             }
@@ -55,83 +94,208 @@ pub fn start_bar_creator(bar_img_out: mpsc::Sender<(fs::File, i32)>,
     }
 
     loop {
-        match combined_in.recv() {
-            Ok((width, mouse_event)) => {
-                if let Some(width) = width {
-                    if width != output_width {
-                        bg_img = config.bg.crop(0, 0, width, config.bar_height);
-                        output_width = width;
+        let (output, event) = match combined_in.recv() {
+            Ok(message) => message,
+            Err(_) => Err("Config or Resize channel disconnected.".to_owned())?,
+        };
+
+        let redraw = match event {
+            BarEvent::Output(output_event) => {
+                // Blocks are recreated when the scale changes, since they are sized with it
+                // New names can select a different `[output]` table, so they recreate it too
+                let changed = match bars.get(&output) {
+                    Some(bar) => {
+                        bar.config.scale != output_event.scale || bar.names != output_event.names
                     }
-                } else if let Some(mouse_event) = mouse_event {
-                    if !propagate_mouse_events(&mut config, output_width, mouse_event)? {
+                    None => false,
+                };
+
+                if output_event.width == 0 || changed {
+                    if let Some(bar) = bars.remove(&output) {
+                        bar.alive.store(false, sync::atomic::Ordering::Relaxed);
+                    }
+
+                    if output_event.width == 0 {
                         continue;
                     }
                 }
 
-                if output_width > 0 {
-                    let bar = create_bar_from_config(&mut config, bg_img.clone(), output_width)?;
-                    bar_img_out.send((img_to_file(bar)?, config.bar_height as i32))?;
+                if !bars.contains_key(&output) {
+                    let bar = create_bar(&config_val, &output_event, combined_out.clone())?;
+                    bars.insert(output, bar);
                 }
+
+                let bar = bars.get_mut(&output).ok_or("Unable to find output.")?;
+                let width = output_event.width * output_event.scale;
+                if width != bar.width {
+                    bar.bg_img = bar.config.bg.crop(0, 0, width, bar.config.bar_height);
+                    bar.width = width;
+                }
+
+                true
+            }
+            BarEvent::Mouse(mut mouse_event) => {
+                let bar = match bars.get_mut(&output) {
+                    Some(bar) => bar,
+                    None => continue,
+                };
+
+                // Panels send positions in logical pixels, negative ones mean outside
+                if mouse_event.x >= 0. {
+                    mouse_event.x *= bar.config.scale as f64;
+                    mouse_event.y *= bar.config.scale as f64;
+                    mouse_event.scale = bar.config.scale;
+                }
+
+                propagate_mouse_events(&mut bar.config, &bar.layout, mouse_event)
+            }
+            BarEvent::Interval => true,
+            BarEvent::Frame => {
+                if let Some(bar) = bars.get_mut(&output) {
+                    bar.frame_pending = false;
+                }
+                false
             }
-            Err(_) => Err("Config or Resize channel disconnected.".to_owned())?,
         };
+
+        if let Some(bar) = bars.get_mut(&output) {
+            bar.dirty = bar.dirty || redraw;
+
+            // Changes are collected until the panel is ready for the next image
+            if bar.dirty && !bar.frame_pending && bar.width > 0 {
+                let (img, layout) =
+                    create_bar_from_config(&mut bar.config, bar.bg_img.clone(), bar.width)?;
+                let bytes = img_to_bytes(img);
+                bar.layout = layout;
+                bar.dirty = false;
+
+                // Frames which look exactly like the last one are skipped
+                let damage = damaged_regions(&bar.last_bytes, &bytes, bar.width);
+                if !damage.is_empty() {
+                    let bar_img = BarImage {
+                        output: output,
+                        bytes: bytes.clone(),
+                        height: bar.config.bar_height as i32,
+                        scale: bar.config.scale as i32,
+                        damage: damage,
+                    };
+                    bar_img_out.send(bar_img)?;
+
+                    bar.last_bytes = bytes;
+                    bar.frame_pending = true;
+                }
+            }
+        }
     }
 }
 
-fn propagate_mouse_events(config: &mut Config,
-                          bar_width: u32,
-                          mut mouse_event: MouseEvent)
-                          -> Result<bool, Box<error::Error>> {
-    let event_x = mouse_event.x as u32;
+// Creates the blocks for a new output and starts their interval notification callbacks
+// The callbacks stop forwarding as soon as `alive` of the bar is set to false
+fn create_bar(config_val: &toml::Value,
+              output_event: &OutputEvent,
+              combined_out: mpsc::Sender<(u32, BarEvent)>)
+              -> Result<Bar, Box<error::Error>> {
+    let mut config = parse_input::parse_settings(config_val,
+                                                 &output_event.names,
+                                                 output_event.scale)?;
+    let output = output_event.id;
+    let alive = sync::Arc::new(sync::atomic::AtomicBool::new(true));
+    let (interval_out, interval_in) = mpsc::channel();
 
-    let last_left_block_index = config.left_blocks.len();
-    let last_center_block_index = last_left_block_index + config.center_blocks.len();
+    // This will spawn threads inside the start_interval methods
+    for element in config.left_blocks
+        .iter_mut()
+        .chain(config.center_blocks.iter_mut())
+        .chain(config.right_blocks.iter_mut()) {
+        element.start_interval(interval_out.clone());
+    }
 
-    let center_images = render_blocks(&mut config.center_blocks)?;
-    let right_images = render_blocks(&mut config.right_blocks)?;
+    // Tag interval notifications with the output
+    {
+        let alive = alive.clone();
+        thread::spawn(move || {
+            while let Ok(_) = interval_in.recv() {
+                if !alive.load(sync::atomic::Ordering::Relaxed) ||
+                   combined_out.send((output, BarEvent::Interval)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
 
-    let center_blocks_width: u32 = center_images.iter().map(|i| i.width()).sum();
-    let center_offset_x = bar_width / 2 - center_blocks_width / 2;
+    Ok(Bar {
+        config: config,
+        names: output_event.names.clone(),
+        width: 0,
+        bg_img: image::DynamicImage::new_rgba8(0, 0),
+        alive: alive,
+        dirty: true,
+        frame_pending: false,
+        last_bytes: Vec::new(),
+        layout: Vec::new(),
+    })
+}
 
-    let right_blocks_width: u32 = right_images.iter().map(|i| i.width()).sum();
-    let right_offset_x = bar_width - right_blocks_width;
+// Hovered blocks receive the event relative to their own position, all others get `None`
+fn propagate_mouse_events(config: &mut Config,
+                          layout: &[BlockRect],
+                          mut mouse_event: MouseEvent)
+                          -> bool {
+    // Negative positions are sent when the pointer has left the bar
+    let hovered = if mouse_event.x >= 0. {
+        block_at(layout, mouse_event.x)
+    } else {
+        None
+    };
 
     let mut redraw = false;
-
-    let mut offset = 0;
     for (i, block) in config.left_blocks
         .iter_mut()
-        .enumerate()
-        .chain(config.center_blocks
-            .iter_mut()
-            .enumerate()
-            .map(|(i, b)| (i + last_left_block_index, b)))
-        .chain(config.right_blocks
-            .iter_mut()
-            .enumerate()
-            .map(|(i, b)| (i + last_center_block_index, b))) {
-        let image = block.render()?;
-        let block_right = offset + image.width();
-
-        if event_x >= offset && event_x <= block_right {
-            mouse_event.x -= offset as f64;
+        .chain(config.center_blocks.iter_mut())
+        .chain(config.right_blocks.iter_mut())
+        .enumerate() {
+        if hovered == Some(i) {
+            mouse_event.x -= layout[i].x as f64;
             if block.mouse_event(Some(mouse_event.clone())) {
                 redraw = true;
             }
         } else if block.mouse_event(None) {
             redraw = true;
         }
+    }
+
+    redraw
+}
 
-        if i == last_left_block_index - 1 {
-            offset = center_offset_x;
-        } else if i == last_center_block_index - 1 {
-            offset = right_offset_x;
-        } else {
-            offset += block_right;
+// Returns the index of the block at the horizontal position `x`
+fn block_at(layout: &[BlockRect], x: f64) -> Option<usize> {
+    layout.iter().position(|rect| x >= rect.x as f64 && x < (rect.x + rect.width) as f64)
+}
+
+// Calculates the position of every block, in the order left, center and right blocks
+fn layout_blocks(left_widths: &[u32],
+                 center_widths: &[u32],
+                 right_widths: &[u32],
+                 bar_width: u32)
+                 -> Vec<BlockRect> {
+    let center_width: u32 = center_widths.iter().sum();
+    let right_width: u32 = right_widths.iter().sum();
+
+    let mut layout = Vec::new();
+    for &(widths, start) in &[(left_widths, 0),
+                              (center_widths, (bar_width / 2).saturating_sub(center_width / 2)),
+                              (right_widths, bar_width.saturating_sub(right_width))] {
+        let mut x = start;
+        for &width in widths {
+            layout.push(BlockRect {
+                x: x,
+                width: width,
+            });
+            x += width;
         }
     }
 
-    Ok(redraw)
+    layout
 }
 
 fn render_blocks(blocks: &mut [Box<Block>]) -> Result<Vec<image::DynamicImage>, Box<error::Error>> {
@@ -140,45 +304,55 @@ fn render_blocks(blocks: &mut [Box<Block>]) -> Result<Vec<image::DynamicImage>,
         .collect::<Result<Vec<image::DynamicImage>, Box<error::Error>>>()?)
 }
 
+// Replaces the images of all blocks which have a shorter version
+fn render_short_blocks(blocks: &mut [Box<Block>],
+                       images: &mut [image::DynamicImage])
+                       -> Result<(), Box<error::Error>> {
+    for (block, image) in blocks.iter_mut().zip(images.iter_mut()) {
+        if let Some(short_image) = block.render_short() {
+            *image = short_image?;
+        }
+    }
+
+    Ok(())
+}
+
+fn image_widths(images: &[image::DynamicImage]) -> Vec<u32> {
+    images.iter().map(|image| image.width()).collect()
+}
+
+// Renders all blocks on top of the background and returns the layout used for it
 fn create_bar_from_config(config: &mut Config,
                           mut bg_img: image::DynamicImage,
                           bar_width: u32)
-                          -> Result<image::DynamicImage, Box<error::Error>> {
-    if let Some(left_image) = combine_elements(&mut config.left_blocks, config.bar_height)? {
-        combine_images(&mut bg_img, &left_image, 0);
+                          -> Result<(image::DynamicImage, Vec<BlockRect>), Box<error::Error>> {
+    let mut left_images = render_blocks(&mut config.left_blocks)?;
+    let mut center_images = render_blocks(&mut config.center_blocks)?;
+    let mut right_images = render_blocks(&mut config.right_blocks)?;
+
+    // Switch to the short version of all blocks when they don't fit on the bar
+    let total_width: u32 = left_images.iter()
+        .chain(center_images.iter())
+        .chain(right_images.iter())
+        .map(|image| image.width())
+        .sum();
+    if total_width > bar_width {
+        render_short_blocks(&mut config.left_blocks, &mut left_images)?;
+        render_short_blocks(&mut config.center_blocks, &mut center_images)?;
+        render_short_blocks(&mut config.right_blocks, &mut right_images)?;
     }
 
-    if let Some(center_image) = combine_elements(&mut config.center_blocks, config.bar_height)? {
-        let offset = bar_width / 2 - center_image.width() / 2;
-        combine_images(&mut bg_img, &center_image, offset);
-    }
+    let layout = layout_blocks(&image_widths(&left_images),
+                               &image_widths(&center_images),
+                               &image_widths(&right_images),
+                               bar_width);
 
-    if let Some(right_image) = combine_elements(&mut config.right_blocks, config.bar_height)? {
-        let offset = bar_width - right_image.width();
-        combine_images(&mut bg_img, &right_image, offset);
+    let images = left_images.iter().chain(center_images.iter()).chain(right_images.iter());
+    for (image, rect) in images.zip(layout.iter()) {
+        combine_images(&mut bg_img, image, rect.x);
     }
 
-    Ok(bg_img)
-}
-
-fn combine_elements(blocks: &mut [Box<Block>],
-                    bar_height: u32)
-                    -> Result<Option<image::DynamicImage>, Box<error::Error>> {
-    if blocks.is_empty() {
-        Ok(None)
-    } else {
-        let images = render_blocks(blocks)?;
-        let width = images.iter().map(|img| img.width()).sum();
-        let mut result_img = image::DynamicImage::new_rgba8(width, bar_height);
-
-        let mut offset = 0;
-        for image in images {
-            combine_images(&mut result_img, &image, offset);
-            offset += image.width();
-        }
-
-        Ok(Some(result_img))
-    }
+    Ok((bg_img, layout))
 }
 
 // Draws the second image on top of the first one with an x-offset
@@ -192,10 +366,9 @@ fn combine_images(first: &mut image::DynamicImage, second: &image::DynamicImage,
     }
 }
 
-fn img_to_file(img: image::DynamicImage) -> Result<fs::File, io::Error> {
-    let mut tmp = tempfile::tempfile()?;
-
-    let mut bytes = Vec::new();
+// Converts the image to ARGB8888 in little endian, as used by Wayland and X11
+fn img_to_bytes(img: image::DynamicImage) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(img.width() as usize * img.height() as usize * 4);
     for pixel in img.pixels() {
         let channels = pixel.2.channels();
         if channels.len() == 4 {
@@ -206,24 +379,83 @@ fn img_to_file(img: image::DynamicImage) -> Result<fs::File, io::Error> {
         }
     }
 
-    let _ = tmp.write(&bytes);
-    let _ = tmp.flush();
+    bytes
+}
+
+// Compares two images column by column and returns the horizontal ranges `(x, width)`
+// which differ, the whole image is damaged if the size has changed
+fn damaged_regions(old: &[u8], new: &[u8], width: u32) -> Vec<(i32, i32)> {
+    if old.len() != new.len() {
+        return vec![(0, width as i32)];
+    }
+
+    let width = width as usize;
+    let height = new.len() / 4 / width;
+
+    let mut regions = Vec::new();
+    let mut start = None;
+    for x in 0..width {
+        let changed = (0..height).any(|y| {
+            let index = (y * width + x) * 4;
+            old[index..index + 4] != new[index..index + 4]
+        });
 
-    Ok(tmp)
+        match start {
+            None if changed => start = Some(x),
+            Some(region_start) if !changed => {
+                regions.push((region_start as i32, (x - region_start) as i32));
+                start = None;
+            }
+            _ => (),
+        }
+    }
+
+    if let Some(region_start) = start {
+        regions.push((region_start as i32, (width - region_start) as i32));
+    }
+
+    regions
 }
 
-#[cfg(test)]
-use parse_input;
 #[test]
-fn render_block_prevent_escape_sequences() {
-    let mut col = image::DynamicImage::new_rgba8(1, 1);
-    col.put_pixel(0, 0, Rgba { data: [255, 0, 255, 255] });
-    let block = ImageElement {
-        bg_col: col.clone(),
-        fg_col: col,
-    };
-    let font = parse_input::get_settings().font;
+fn damaged_regions_finds_changed_columns() {
+    // Image with a width of 4 and a height of 2
+    let old = vec![0u8; 32];
+    let mut new = old.clone();
+    assert!(damaged_regions(&old, &new, 4).is_empty());
+
+    // Change the first pixel in the second row and the last column
+    new[16] = 255;
+    new[12] = 255;
+    new[31] = 255;
+    assert_eq!(damaged_regions(&old, &new, 4), vec![(0, 1), (3, 1)]);
+
+    assert_eq!(damaged_regions(&[], &new, 4), vec![(0, 4)]);
+}
+
+#[test]
+fn layout_blocks_places_left_center_and_right_blocks() {
+    let layout = layout_blocks(&[10, 20], &[30], &[5, 15], 200);
+    let rects: Vec<(u32, u32)> = layout.iter().map(|rect| (rect.x, rect.width)).collect();
+    assert_eq!(rects, vec![(0, 10), (10, 20), (85, 30), (180, 5), (185, 15)]);
+}
 
-    let result = block.render(30, &font).unwrap();
-    assert_eq!(result.get_pixel(0, 0), Rgba { data: [255, 0, 255, 255] });
+#[test]
+fn block_at_hits_left_center_and_right_blocks() {
+    let layout = layout_blocks(&[10, 20], &[30], &[5, 15], 200);
+
+    // Left
+    assert_eq!(block_at(&layout, 0.), Some(0));
+    assert_eq!(block_at(&layout, 15.), Some(1));
+    assert_eq!(block_at(&layout, 29.5), Some(1));
+
+    // Center
+    assert_eq!(block_at(&layout, 50.), None);
+    assert_eq!(block_at(&layout, 85.), Some(2));
+    assert_eq!(block_at(&layout, 114.), Some(2));
+
+    // Right
+    assert_eq!(block_at(&layout, 184.), Some(3));
+    assert_eq!(block_at(&layout, 199.), Some(4));
+    assert_eq!(block_at(&layout, 200.), None);
 }
@@ -0,0 +1,196 @@
+use std::fs;
+use std::io;
+use std::path;
+use rusttype;
+use std::error;
+use std::process;
+use std::sync::Arc;
+use std::io::Read;
+
+use shaping;
+use parse_input;
+
+// Searched recursively when fontconfig is not available, relative paths are inside the home dir
+const FONT_DIRS: [&'static str; 5] = ["/usr/share/fonts",
+                                      "/usr/local/share/fonts",
+                                      ".local/share/fonts",
+                                      ".fonts",
+                                      ".nix-profile/share/fonts"];
+
+// A font face with the HarfBuzz font used for shaping, both share the data of the file
+#[derive(Clone)]
+pub struct Font {
+    pub rusttype_font: rusttype::Font<'static>,
+    pub shaper: Arc<shaping::Shaper>,
+}
+
+// Loads a font by path or by name
+// Paths can select a face in a collection with a `:index` suffix like `~/fonts/font.ttc:1`
+// Everything else is a fontconfig pattern like `DejaVu Sans Mono:bold`
+pub fn load_font(font_string: &str) -> Result<Font, Box<error::Error>> {
+    let (font_path, index) = if is_path(font_string) {
+        let home = parse_input::get_home_dir()?;
+        let (font_path, index) = split_index(font_string);
+        (font_path.replace("$", &home).replace("~", &home), index)
+    } else {
+        find_font(font_string)?
+    };
+
+    let font_file = fs::File::open(&font_path)?;
+    let font_data = font_file.bytes().collect::<Result<Vec<u8>, io::Error>>()?;
+    let font_data = Arc::new(font_data.into_boxed_slice());
+
+    // Rusttype can only rasterize TrueType outlines
+    if is_cff(&font_data, index) {
+        return Err(format!("{} uses CFF outlines, please use a TrueType font.", font_path).into());
+    }
+
+    let collection = rusttype::FontCollection::from_bytes(font_data.clone());
+    let rusttype_font = collection.font_at(index)
+        .ok_or_else(|| format!("Unable to find face {} in {}.", index, font_path))?;
+
+    Ok(Font {
+        rusttype_font: rusttype_font,
+        shaper: Arc::new(shaping::Shaper::new(font_data, index)),
+    })
+}
+
+fn is_path(font_string: &str) -> bool {
+    font_string.starts_with('/') || font_string.starts_with('~') ||
+    font_string.starts_with('$') || font_string.starts_with('.')
+}
+
+// Splits the face index from a path, the first face is used without index
+fn split_index(font_string: &str) -> (&str, usize) {
+    if let Some(separator) = font_string.rfind(':') {
+        if let Ok(index) = font_string[separator + 1..].parse() {
+            return (&font_string[..separator], index);
+        }
+    }
+
+    (font_string, 0)
+}
+
+// Returns the path and face index of the font fontconfig considers the best match
+// Falls back to searching the font directories if `fc-match` can't be used
+fn find_font(pattern: &str) -> Result<(String, usize), Box<error::Error>> {
+    let fc_match = process::Command::new("fc-match")
+        .arg("--format=%{file}\n%{index}")
+        .arg(pattern)
+        .output();
+
+    if let Ok(output) = fc_match {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let mut lines = stdout.lines();
+        match (output.status.success(), lines.next(), lines.next()) {
+            (true, Some(file), index) if !file.is_empty() => {
+                let index = index.and_then(|index| index.parse().ok()).unwrap_or(0);
+                return Ok((file.to_owned(), index));
+            }
+            _ => (),
+        }
+    }
+
+    search_font_dirs(pattern)
+        .map(|font_path| (font_path, 0))
+        .ok_or_else(|| format!("Unable to find a font matching {}.", pattern).into())
+}
+
+// Finds a font file with the family and style in its name, `DejaVu Sans Mono:bold` matches
+// `DejaVuSansMono-Bold.ttf`
+fn search_font_dirs(pattern: &str) -> Option<String> {
+    let mut parts = pattern.splitn(2, ':');
+    let family = normalize_name(parts.next().unwrap_or(""));
+    let style = normalize_name(parts.next().unwrap_or(""));
+    let names = if style.is_empty() {
+        vec![family.clone(), family + "regular"]
+    } else {
+        vec![family + &style]
+    };
+
+    let home = parse_input::get_home_dir().unwrap_or_default();
+    for font_dir in FONT_DIRS.iter() {
+        let font_dir = path::Path::new(&home).join(font_dir);
+        if let Some(font_path) = search_dir(&font_dir, &names) {
+            return Some(font_path.to_string_lossy().to_string());
+        }
+    }
+
+    None
+}
+
+fn search_dir(dir: &path::Path, names: &[String]) -> Option<path::PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return None,
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let entry_path = entry.path();
+        if entry_path.is_dir() {
+            if let Some(font_path) = search_dir(&entry_path, names) {
+                return Some(font_path);
+            }
+            continue;
+        }
+
+        let extension = entry_path.extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let stem = entry_path.file_stem()
+            .map(|stem| normalize_name(&stem.to_string_lossy()))
+            .unwrap_or_default();
+
+        if (extension == "ttf" || extension == "ttc" || extension == "otf") &&
+           names.contains(&stem) {
+            return Some(entry_path);
+        }
+    }
+
+    None
+}
+
+// Lowercase name without spaces, dashes or underscores
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+// OpenType fonts with CFF outlines start with `OTTO` instead of the TrueType version
+// Collections start with `ttcf` followed by the offsets of every face
+fn is_cff(font_data: &[u8], index: usize) -> bool {
+    let offset = if font_data.starts_with(b"ttcf") {
+        let position = 12 + index * 4;
+        match font_data.get(position..position + 4) {
+            Some(offset) => {
+                (offset[0] as usize) << 24 | (offset[1] as usize) << 16 |
+                (offset[2] as usize) << 8 | offset[3] as usize
+            }
+            None => return false,
+        }
+    } else {
+        0
+    };
+
+    font_data.get(offset..offset + 4) == Some(&b"OTTO"[..])
+}
+
+#[test]
+fn split_index_only_accepts_numbers() {
+    assert_eq!(split_index("~/fonts/font.ttc:2"), ("~/fonts/font.ttc", 2));
+    assert_eq!(split_index("~/fonts/font.ttf"), ("~/fonts/font.ttf", 0));
+    assert_eq!(split_index("~/a:b/font.ttf"), ("~/a:b/font.ttf", 0));
+}
+
+#[test]
+fn is_cff_checks_collection_faces() {
+    let mut collection = b"ttcf\0\0\0\0\0\0\0\x02\0\0\0\x14\0\0\0\x18".to_vec();
+    collection.extend_from_slice(b"\0\x01\0\0OTTO");
+
+    assert!(!is_cff(&collection, 0));
+    assert!(is_cff(&collection, 1));
+    assert!(!is_cff(&collection, 2));
+    assert!(is_cff(b"OTTO", 0));
+}
@@ -4,21 +4,30 @@ extern crate wayland_client;
 extern crate wayland_sys;
 #[macro_use]
 extern crate lazy_static;
-extern crate tempfile;
+#[macro_use]
+extern crate bitflags;
+#[macro_use]
+extern crate serde_json;
+extern crate libc;
 extern crate rusttype;
 extern crate xcb_util;
 extern crate image;
 extern crate regex;
 extern crate toml;
 extern crate xcb;
+extern crate unicode_bidi;
 
 use std::thread;
 use std::sync::mpsc;
 
 mod xorg;
+mod shm;
+mod font;
 mod mouse;
+mod output;
 mod modules;
 mod wayland;
+mod shaping;
 mod create_bar;
 mod parse_input;
 
@@ -29,16 +38,23 @@ fn main() {
     let (bar_img_out, bar_img_in) = mpsc::channel();
     let (resize_out, resize_in) = mpsc::channel();
     let (mouse_out, mouse_in) = mpsc::channel();
+    let (frame_out, frame_in) = mpsc::channel();
+
+    let config_val = parse_input::read_config_value().unwrap();
+    let panel_config = parse_input::parse_panel_settings(&config_val).unwrap();
 
     {
         thread::spawn(move || {
-            create_bar::start_bar_creator(bar_img_out, resize_in, mouse_in).unwrap();
+            create_bar::start_bar_creator(config_val, bar_img_out, resize_in, mouse_in, frame_in)
+                .unwrap();
         });
     }
 
     if wayland::wayland_server_available() {
-        wayland::start_wayland_panel(bar_img_in, resize_out, mouse_out).unwrap();
+        wayland::start_wayland_panel(panel_config, bar_img_in, resize_out, mouse_out, frame_out)
+            .unwrap();
     } else {
-        xorg::start_xorg_panel(bar_img_in, resize_out, mouse_out).unwrap();
+        xorg::start_xorg_panel(panel_config, bar_img_in, resize_out, mouse_out, frame_out)
+            .unwrap();
     }
 }
@@ -1,11 +1,10 @@
 use toml;
-use image;
-use std::cmp;
-use rusttype;
+use image::{self, GenericImage};
 use std::time;
 use std::error;
 use std::thread;
 use std::process;
+use std::io::Read;
 use std::sync::{self, mpsc};
 
 use mouse;
@@ -13,23 +12,164 @@ use modules;
 use parse_input;
 use modules::text;
 
+// Exit code used by i3blocks for urgent blocks
+const URGENT_CODE: i32 = 33;
+
 pub struct CommandBlock {
-    bar_height: u32,
-    font_height: u32,
-    font: rusttype::Font<'static>,
-    bg_col: image::DynamicImage,
-    fg_col: image::Rgba<u8>,
-    width: u32,
-    spacing: u32,
-    command: String,
+    style: text::TextStyle,
     interval: u32,
     cache: sync::Arc<sync::Mutex<Option<image::DynamicImage>>>,
+    worker: Worker,
+    error_bg_col: image::DynamicImage,
+    error_fg_col: image::Rgba<u8>,
+    normal_cols: StateColors,
+    warning_cols: StateColors,
+    critical_cols: StateColors,
+    redraw_out: Option<mpsc::Sender<(Option<u32>, Option<mouse::MouseEvent>)>>,
     hover_bg_col: image::DynamicImage,
     hover_fg_col: image::Rgba<u8>,
-    click_command: Option<String>,
+    mouse_commands: mouse::MouseCommands,
+    name: String,
+    // Width of the last rendered image, passed to mouse commands
+    rendered_width: u32,
     hover: bool,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum State {
+    NORMAL,
+    WARNING,
+    CRITICAL,
+}
+
+// Colors of a state, the normal state defaults to the colors of the block
+struct StateColors {
+    bg: image::DynamicImage,
+    fg: image::Rgba<u8>,
+}
+
+// Output of a command which exited successfully
+#[derive(Clone, Debug, PartialEq)]
+struct Status {
+    text: String,
+    // Displayed instead of the text when the bar is too small
+    short_text: Option<String>,
+    // Replaces the foreground color of the normal state
+    fg: Option<image::Rgba<u8>>,
+    state: State,
+}
+
+// Result of the last command, shared with the threads running it
+struct CommandOutput {
+    // Last good output, displayed while a new one is pending
+    status: Status,
+    error: Option<String>,
+    running: bool,
+}
+
+// Everything the command has written before exiting
+struct ProcessOutput {
+    stdout: String,
+    stderr: String,
+    // Not available when the process was killed by a signal
+    code: Option<i32>,
+}
+
+// Runs the command of a block outside of the bar creator thread
+#[derive(Clone)]
+struct Worker {
+    command: String,
+    // Time in milliseconds until the command is killed, 0 waits forever
+    timeout: u32,
+    // Exit codes which select a state, all others are errors
+    codes: Vec<(i32, State)>,
+    // Read stdout as text, short text and color on separate lines
+    i3blocks: bool,
+    output: sync::Arc<sync::Mutex<CommandOutput>>,
+    cache: sync::Arc<sync::Mutex<Option<image::DynamicImage>>>,
+}
+
+impl Worker {
+    // Blocks until the command is done, returns false if it was already running
+    fn update(&self) -> bool {
+        {
+            let mut output = self.output.lock().unwrap(); // TODO: Not unwrap?
+            if output.running {
+                return false;
+            }
+            output.running = true;
+        }
+
+        let result = run_command(&self.command, self.timeout)
+            .and_then(|process_output| self.interpret(process_output));
+        self.set_output(result);
+        true
+    }
+
+    // Selects the state based on the exit code
+    // Unknown exit codes and output only on stderr are reported as errors
+    fn interpret(&self, process_output: ProcessOutput) -> Result<Status, String> {
+        let state = process_output.code
+            .and_then(|code| self.codes.iter().find(|&&(c, _)| c == code).map(|&(_, s)| s));
+        let stderr = process_output.stderr.lines().next().unwrap_or("").trim().to_owned();
+
+        match state {
+            Some(_) if process_output.stdout.trim().is_empty() && !stderr.is_empty() => Err(stderr),
+            Some(state) => Ok(self.parse_status(&process_output.stdout, state)),
+            None if !stderr.is_empty() => Err(stderr),
+            None => {
+                match process_output.code {
+                    Some(code) => Err(format!("exit code: {}", code)),
+                    None => Err("killed".to_owned()),
+                }
+            }
+        }
+    }
+
+    fn parse_status(&self, stdout: &str, state: State) -> Status {
+        if !self.i3blocks {
+            return Status {
+                text: stdout.to_owned(),
+                short_text: None,
+                fg: None,
+                state: state,
+            };
+        }
+
+        let mut lines = stdout.lines();
+        let text = lines.next().unwrap_or("").to_owned();
+        let short_text = match lines.next() {
+            Some(short_text) if !short_text.trim().is_empty() => Some(short_text.to_owned()),
+            _ => None,
+        };
+        let fg = lines.next().and_then(|color| parse_input::parse_color(color.trim()));
+
+        Status {
+            text: text,
+            short_text: short_text,
+            fg: fg,
+            state: state,
+        }
+    }
+
+    // The cache is only locked after the output to prevent deadlocks with `render`
+    fn set_output(&self, result: Result<Status, String>) {
+        {
+            let mut output = self.output.lock().unwrap(); // TODO: Not unwrap?
+            output.running = false;
+            match result {
+                Ok(status) => {
+                    output.status = status;
+                    output.error = None;
+                }
+                Err(error) => output.error = Some(error),
+            }
+        }
+
+        *self.cache.lock().unwrap() = None; // TODO: Not unwrap?
+    }
+}
+
 // Unwraps cannot fail
 impl CommandBlock {
     pub fn create(config: parse_input::Config,
@@ -38,63 +178,153 @@ impl CommandBlock {
         let command = value.lookup("command").ok_or("Could not find command in a command module.")?;
         let command = command.as_str()
             .ok_or("process::Command in command module is not a String.")?;
-        let font_height = cmp::min(config.bar_height, config.font_height.unwrap());
+
+        let error_bg_col = parse_input::toml_value_to_image(value, "error_bg")
+            .unwrap_or_else(|_| config.bg.clone());
+        let error_fg_col = parse_input::toml_value_to_rgba(value, "error_fg")
+            .unwrap_or(image::Rgba { data: [255, 0, 0, 255] });
+
+        // States are configured in `[block.normal]`, `[block.warning]` and `[block.critical]`
+        let mut urgent_bg = image::DynamicImage::new_rgba8(1, 1);
+        urgent_bg.put_pixel(0, 0, image::Rgba { data: [144, 0, 0, 255] });
+        let (normal_cols, normal_codes) =
+            state_from_toml(value, "normal", &[0], config.fg, &config.bg);
+        let yellow = image::Rgba { data: [255, 255, 0, 255] };
+        let (warning_cols, warning_codes) =
+            state_from_toml(value, "warning", &[], yellow, &config.bg);
+        let (critical_cols, critical_codes) =
+            state_from_toml(value, "critical", &[URGENT_CODE], config.fg, &urgent_bg);
+
+        let mut codes = Vec::new();
+        codes.extend(normal_codes.into_iter().map(|code| (code, State::NORMAL)));
+        codes.extend(warning_codes.into_iter().map(|code| (code, State::WARNING)));
+        codes.extend(critical_codes.into_iter().map(|code| (code, State::CRITICAL)));
+
+        let cache = sync::Arc::new(sync::Mutex::new(None));
+        let worker = Worker {
+            command: command.to_owned(),
+            timeout: parse_input::toml_value_to_integer(value, "timeout").unwrap_or(0),
+            codes: codes,
+            i3blocks: parse_input::toml_value_to_bool(value, "i3blocks").unwrap_or(false),
+            output: sync::Arc::new(sync::Mutex::new(CommandOutput {
+                status: Status {
+                    text: String::new(),
+                    short_text: None,
+                    fg: None,
+                    state: State::NORMAL,
+                },
+                error: None,
+                running: false,
+            })),
+            cache: cache.clone(),
+        };
 
         // Read mouse values from toml
         let mut hover_bg_col = config.bg.clone();
         let mut hover_fg_col = config.fg;
-        let mut click_command = None;
 
         if let Some(hover_table) = value.lookup("mouse") {
             hover_bg_col = parse_input::toml_value_to_image(hover_table, "hover_bg")
                 .unwrap_or(hover_bg_col);
             hover_fg_col = parse_input::toml_value_to_rgba(hover_table, "hover_fg")
                 .unwrap_or(hover_fg_col);
-            click_command = parse_input::toml_value_to_string(hover_table, "command").ok();
         }
 
         Ok(Box::new(CommandBlock {
-            bar_height: config.bar_height,
-            font_height: font_height,
-            font: config.font.unwrap(),
-            bg_col: config.bg,
-            fg_col: config.fg,
-            width: config.width,
-            spacing: config.spacing,
-            command: command.to_owned(),
+            style: text::TextStyle::from_config(&config),
             interval: config.interval,
-            cache: sync::Arc::new(sync::Mutex::new(None)),
+            cache: cache,
+            worker: worker,
+            error_bg_col: error_bg_col,
+            error_fg_col: error_fg_col,
+            normal_cols: normal_cols,
+            warning_cols: warning_cols,
+            critical_cols: critical_cols,
+            redraw_out: None,
             hover_bg_col: hover_bg_col,
             hover_fg_col: hover_fg_col,
-            click_command: click_command,
+            mouse_commands: mouse::MouseCommands::from_toml(value.lookup("mouse")),
+            name: config.name,
+            rendered_width: 0,
             hover: false,
         }))
     }
+
+    // Renders the last output of the command, using the short text if requested and available
+    fn render_status(&self, short: bool) -> Result<image::DynamicImage, Box<error::Error>> {
+        let (status, error) = {
+            let output = self.worker.output.lock().map_err(|e| e.to_string())?;
+            (output.status.clone(), output.error.clone())
+        };
+
+        let text = match (short, status.short_text) {
+            (true, Some(short_text)) => short_text,
+            _ => status.text,
+        };
+        let (text, fg_col, bg_col) = match (error, status.state) {
+            (Some(error), _) => (error, self.error_fg_col, &self.error_bg_col),
+            (None, State::WARNING) => (text, self.warning_cols.fg, &self.warning_cols.bg),
+            (None, State::CRITICAL) => (text, self.critical_cols.fg, &self.critical_cols.bg),
+            (None, State::NORMAL) if self.hover => (text, self.hover_fg_col, &self.hover_bg_col),
+            (None, State::NORMAL) => {
+                (text, status.fg.unwrap_or(self.normal_cols.fg), &self.normal_cols.bg)
+            }
+        };
+
+        let mut text_block = text::TextBlock::for_text(&self.style, text, fg_col, bg_col);
+
+        text_block.render()
+    }
 }
 
 impl modules::Block for CommandBlock {
     fn start_interval(&mut self,
                       interval_out: mpsc::Sender<(Option<u32>, Option<mouse::MouseEvent>)>) {
-        if self.interval > 0 {
-            let interval = self.interval as u64;
-            let cache = self.cache.clone();
-            thread::spawn(move || {
-                loop {
-                    thread::sleep(time::Duration::from_millis(interval));
-                    let mut cache_lock = cache.lock().unwrap(); // TODO: Not unwrap?
-                    *cache_lock = None;
-                    interval_out.send((None, None)).unwrap(); // TODO: Not unwrap?
+        self.redraw_out = Some(interval_out.clone());
+
+        // Without interval the command is only run once
+        let interval = self.interval as u64;
+        let worker = self.worker.clone();
+        thread::spawn(move || {
+            loop {
+                worker.update();
+
+                // Stop once nobody is interested in updates anymore
+                if interval_out.send((None, None)).is_err() || interval == 0 {
+                    break;
                 }
-            });
-        }
+                thread::sleep(time::Duration::from_millis(interval));
+            }
+        });
     }
 
     fn mouse_event(&mut self, mouse_event: Option<mouse::MouseEvent>) -> bool {
         if let Some(ref mouse_event) = mouse_event {
-            if let Some(mouse::ButtonState::RELEASED) = mouse_event.state {
-                if let Some(ref command) = self.click_command {
-                    let _ = process::Command::new("sh").arg("-c").arg(&command).spawn();
-                }
+            let (width, height) = (self.rendered_width, self.style.bar_height);
+            let replace_text = self.mouse_commands.replace_text;
+            if !self.mouse_commands.refresh && !replace_text {
+                self.mouse_commands.spawn(mouse_event, &self.name, width, height);
+            } else if let Some(child) =
+                self.mouse_commands.run(mouse_event, &self.name, width, height) {
+                let worker = self.worker.clone();
+                let redraw_out = self.redraw_out.clone();
+                thread::spawn(move || {
+                    let output = match child.wait_with_output() {
+                        Ok(output) => output,
+                        Err(_) => return,
+                    };
+
+                    if replace_text {
+                        let text = String::from_utf8_lossy(&output.stdout);
+                        worker.set_output(Ok(worker.parse_status(&text, State::NORMAL)));
+                    } else if !worker.update() {
+                        return;
+                    }
+
+                    if let Some(redraw_out) = redraw_out {
+                        let _ = redraw_out.send((None, None));
+                    }
+                });
             }
         }
 
@@ -114,33 +344,182 @@ impl modules::Block for CommandBlock {
             return Ok(cache.clone());
         }
 
-        let output = process::Command::new("sh").arg("-c").arg(&self.command).output()?;
-        let text = String::from_utf8_lossy(&output.stdout);
+        let image = self.render_status(false)?;
+        self.rendered_width = image.width();
+        *cache = Some(image.clone());
+        Ok(image)
+    }
 
-        let (fg_col, bg_col) = if self.hover {
-            (self.hover_fg_col, &self.hover_bg_col)
-        } else {
-            (self.fg_col, &self.bg_col)
+    fn render_short(&mut self) -> Option<Result<image::DynamicImage, Box<error::Error>>> {
+        let has_short_text = match self.worker.output.lock() {
+            Ok(output) => output.error.is_none() && output.status.short_text.is_some(),
+            Err(_) => false,
         };
+        if !has_short_text {
+            return None;
+        }
 
-        let mut text_block = text::TextBlock {
-            bar_height: self.bar_height,
-            font_height: self.font_height,
-            font: self.font.clone(),
-            bg_col: bg_col.clone(),
-            fg_col: fg_col,
-            text: text.to_string(),
-            width: self.width,
-            spacing: self.spacing,
-            cache: None,
-            hover_bg_col: self.hover_bg_col.clone(),
-            hover_fg_col: self.hover_fg_col,
-            click_command: self.click_command.clone(),
-            hover: false,
-        };
+        let image = self.render_status(true);
+        if let Ok(ref image) = image {
+            self.rendered_width = image.width();
+        }
+        Some(image)
+    }
+}
 
-        let image = text_block.render()?;
-        *cache = Some(image.clone());
-        Ok(image)
+// Reads the colors and exit codes of a state, falling back to the defaults
+fn state_from_toml(value: &toml::Value,
+                   name: &str,
+                   default_codes: &[i32],
+                   default_fg: image::Rgba<u8>,
+                   default_bg: &image::DynamicImage)
+                   -> (StateColors, Vec<i32>) {
+    let state_val = match value.lookup(name) {
+        Some(state_val) => state_val,
+        None => {
+            return (StateColors {
+                        bg: default_bg.clone(),
+                        fg: default_fg,
+                    },
+                    default_codes.to_vec())
+        }
+    };
+
+    let colors = StateColors {
+        bg: parse_input::toml_value_to_image(state_val, "bg")
+            .unwrap_or_else(|_| default_bg.clone()),
+        fg: parse_input::toml_value_to_rgba(state_val, "fg").unwrap_or(default_fg),
+    };
+    let codes = match state_val.lookup("codes").and_then(|codes| codes.as_slice()) {
+        Some(codes) => {
+            codes.iter()
+                .filter_map(|code| code.as_integer())
+                .map(|code| code as i32)
+                .collect()
+        }
+        None => default_codes.to_vec(),
+    };
+
+    (colors, codes)
+}
+
+fn run_command(command: &str, timeout: u32) -> Result<ProcessOutput, String> {
+    let mut child = modules::shell_command(command)
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+
+    // Read output while waiting, commands could block on a full pipe otherwise
+    let stdout_in = read_pipe(child.stdout.take().ok_or("stdout not piped")?);
+    let stderr_in = read_pipe(child.stderr.take().ok_or("stderr not piped")?);
+
+    // Waiting happens on a new thread, so the timeout can be applied while receiving the status
+    let start = time::Instant::now();
+    let id = child.id();
+    let (status_out, status_in) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = status_out.send(child.wait());
+    });
+    let status = recv_until_timeout(&status_in, id, start, timeout)?.map_err(|e| e.to_string())?;
+
+    // Background processes can keep the pipes open after the command has exited
+    let stdout = recv_until_timeout(&stdout_in, id, start, timeout)?;
+    let stderr = recv_until_timeout(&stderr_in, id, start, timeout)?;
+    Ok(ProcessOutput {
+        stdout: String::from_utf8_lossy(&stdout).to_string(),
+        stderr: String::from_utf8_lossy(&stderr).to_string(),
+        code: status.code(),
+    })
+}
+
+// Waits for a message of a helper thread with the remaining time of the timeout
+// The process group is killed once the timeout has expired
+fn recv_until_timeout<T>(message_in: &mpsc::Receiver<T>,
+                         id: u32,
+                         start: time::Instant,
+                         timeout: u32)
+                         -> Result<T, String> {
+    if timeout == 0 {
+        return message_in.recv().map_err(|e| e.to_string());
+    }
+
+    let timeout = time::Duration::from_millis(timeout as u64);
+    let remaining = timeout.checked_sub(start.elapsed()).unwrap_or(time::Duration::from_millis(0));
+    match message_in.recv_timeout(remaining) {
+        Ok(message) => Ok(message),
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            modules::kill_process_group_id(id);
+            Err("timed out".to_owned())
+        }
+        Err(e) => Err(e.to_string()),
     }
 }
+
+// Reads everything from the pipe on a new thread and sends it once the pipe is closed
+fn read_pipe<R: Read + Send + 'static>(mut pipe: R) -> mpsc::Receiver<Vec<u8>> {
+    let (bytes_out, bytes_in) = mpsc::channel();
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = pipe.read_to_end(&mut bytes);
+        let _ = bytes_out.send(bytes);
+    });
+    bytes_in
+}
+
+#[cfg(test)]
+fn test_worker(codes: Vec<(i32, State)>, i3blocks: bool) -> Worker {
+    Worker {
+        command: String::new(),
+        timeout: 0,
+        codes: codes,
+        i3blocks: i3blocks,
+        output: sync::Arc::new(sync::Mutex::new(CommandOutput {
+            status: Status {
+                text: String::new(),
+                short_text: None,
+                fg: None,
+                state: State::NORMAL,
+            },
+            error: None,
+            running: false,
+        })),
+        cache: sync::Arc::new(sync::Mutex::new(None)),
+    }
+}
+
+#[test]
+fn interpret_maps_exit_codes_to_states() {
+    let worker = test_worker(vec![(0, State::NORMAL), (URGENT_CODE, State::CRITICAL)], false);
+    let process_output = |stdout: &str, stderr: &str, code| {
+        ProcessOutput {
+            stdout: stdout.to_owned(),
+            stderr: stderr.to_owned(),
+            code: code,
+        }
+    };
+
+    assert_eq!(worker.interpret(process_output("a", "", Some(0))).unwrap().state,
+               State::NORMAL);
+    assert_eq!(worker.interpret(process_output("a", "", Some(33))).unwrap().state,
+               State::CRITICAL);
+    assert_eq!(worker.interpret(process_output("", "failed\nmore", Some(0))),
+               Err("failed".to_owned()));
+    assert_eq!(worker.interpret(process_output("a", "", Some(1))),
+               Err("exit code: 1".to_owned()));
+}
+
+#[test]
+fn parse_status_reads_i3blocks_lines() {
+    let worker = test_worker(Vec::new(), true);
+
+    let status = worker.parse_status("full\nshort\n#FF0000\n", State::WARNING);
+    assert_eq!(status.text, "full");
+    assert_eq!(status.short_text, Some("short".to_owned()));
+    assert_eq!(status.fg, Some(image::Rgba { data: [255, 0, 0, 255] }));
+    assert_eq!(status.state, State::WARNING);
+
+    let status = worker.parse_status("full\n\nred\n", State::NORMAL);
+    assert_eq!(status.short_text, None);
+    assert_eq!(status.fg, None);
+}
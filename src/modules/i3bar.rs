@@ -0,0 +1,422 @@
+use toml;
+use std::io;
+use std::cmp;
+use std::error;
+use std::process;
+use serde_json;
+use std::io::Write;
+use std::sync::{self, mpsc};
+use image::{self, GenericImage};
+
+use mouse;
+use modules;
+use parse_input;
+use modules::text;
+
+// Defaults used by i3bar
+const SEPARATOR_BLOCK_WIDTH: u32 = 9;
+const SEPARATOR_COL: [u8; 4] = [102, 102, 102, 255];
+const URGENT_FG_COL: [u8; 4] = [255, 255, 255, 255];
+const URGENT_BG_COL: [u8; 4] = [144, 0, 0, 255];
+
+lazy_static! {
+    // Status lines from stdin, shared by the blocks of all bars
+    // Click events are dropped, stdout does not belong to the program writing the status lines
+    static ref STDIN_STREAM: sync::Arc<sync::Mutex<modules::LineStream<State>>> =
+        modules::LineStream::from_stdin(State::new());
+}
+
+// Displays the status lines of a program using the i3bar protocol
+// The program is started with `command`, without it the status lines are read from stdin
+pub struct I3barBlock {
+    style: text::TextStyle,
+    bg_col: image::DynamicImage,
+    fg_col: image::Rgba<u8>,
+    scale: u32,
+    process_group: modules::ProcessGroup,
+    stream: sync::Arc<sync::Mutex<modules::LineStream<State>>>,
+    // Image and the generation of the status line it was rendered from
+    cache: Option<(u64, image::DynamicImage)>,
+    // Position, width and item index of every item in the last rendered image
+    item_rects: Vec<(u32, u32, usize)>,
+}
+
+// Latest state of an i3bar protocol stream
+struct State {
+    click_events: bool,
+    items: Vec<Item>,
+    // Click events are written here, the infinite array is opened with the first event
+    click_out: Option<Box<io::Write + Send>>,
+    clicks_sent: bool,
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            click_events: false,
+            items: Vec::new(),
+            click_out: None,
+            clicks_sent: false,
+        }
+    }
+}
+
+impl modules::LineProtocol for State {
+    // Reads the header and the infinite array of status lines
+    fn handle_line(&mut self, line: &str) -> bool {
+        let value = match parse_line(line) {
+            Some(value) => value,
+            None => return false,
+        };
+
+        if let Some(items) = value.as_array() {
+            self.items = items.iter().filter_map(item_from_json).collect();
+            return true;
+        }
+
+        if value.get("version").is_some() {
+            self.click_events = value.get("click_events")
+                .and_then(|click_events| click_events.as_bool())
+                .unwrap_or(false);
+        }
+        false
+    }
+
+    fn set_input(&mut self, input: process::ChildStdin) {
+        self.click_out = Some(Box::new(input));
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum MinWidth {
+    Pixels(u32),
+    // Wide enough to fit the text
+    Text(String),
+}
+
+// A single status object of a status line
+#[derive(Clone, Debug, PartialEq)]
+struct Item {
+    full_text: String,
+    name: Option<String>,
+    instance: Option<String>,
+    color: Option<image::Rgba<u8>>,
+    background: Option<image::Rgba<u8>>,
+    border: Option<image::Rgba<u8>>,
+    min_width: Option<MinWidth>,
+    align: String,
+    separator: bool,
+    separator_block_width: u32,
+    urgent: bool,
+    // Pango markup in the text
+    markup: bool,
+}
+
+// Unwraps cannot fail
+impl I3barBlock {
+    pub fn create(config: parse_input::Config,
+                  value: &toml::Value)
+                  -> Result<Box<modules::Block>, Box<error::Error>> {
+        let command = parse_input::toml_value_to_string(value, "command").ok();
+
+        // Items are as wide as their text, markup is enabled per item
+        let mut style = text::TextStyle::from_config(&config);
+        style.width = 0;
+
+        let stream = match command {
+            Some(command) => modules::LineStream::new(Some(command), State::new()),
+            None => STDIN_STREAM.clone(),
+        };
+
+        Ok(Box::new(I3barBlock {
+            style: style,
+            bg_col: config.bg,
+            fg_col: config.fg,
+            scale: config.scale,
+            process_group: modules::ProcessGroup::new(),
+            stream: stream,
+            cache: None,
+            item_rects: Vec::new(),
+        }))
+    }
+
+    fn render_text(&self,
+                   text: &str,
+                   markup: bool,
+                   fg_col: image::Rgba<u8>,
+                   bg_col: &image::DynamicImage)
+                   -> Result<image::DynamicImage, Box<error::Error>> {
+        let mut text_block =
+            text::TextBlock::for_text(&self.style, text.to_owned(), fg_col, bg_col);
+        text_block.markup = markup;
+
+        text_block.render()
+    }
+
+    fn render_item(&self, item: &Item) -> Result<image::DynamicImage, Box<error::Error>> {
+        let (fg_col, bg_col) = if item.urgent {
+            (image::Rgba { data: URGENT_FG_COL }, color_image(image::Rgba { data: URGENT_BG_COL }))
+        } else {
+            let bg_col = match item.background {
+                Some(background) => color_image(background),
+                None => self.bg_col.clone(),
+            };
+            (item.color.unwrap_or(self.fg_col), bg_col)
+        };
+
+        let text_image = self.render_text(&item.full_text, item.markup, fg_col, &bg_col)?;
+        let min_width = match item.min_width {
+            Some(MinWidth::Pixels(width)) => width * self.scale,
+            Some(MinWidth::Text(ref text)) => {
+                self.render_text(text, item.markup, fg_col, &bg_col)?.width()
+            }
+            None => 0,
+        };
+
+        let width = cmp::max(text_image.width(), min_width);
+        let x_offset = match item.align.as_str() {
+            "center" => (width - text_image.width()) / 2,
+            "right" => width - text_image.width(),
+            _ => 0,
+        };
+
+        let mut image = image::DynamicImage::new_rgba8(width, self.style.bar_height);
+        fill(&mut image, &bg_col, 0, width);
+        for x in 0..text_image.width() {
+            for y in 0..text_image.height() {
+                image.put_pixel(x + x_offset, y, text_image.get_pixel(x, y));
+            }
+        }
+
+        if let Some(border) = item.border {
+            for x in 0..width {
+                for y in 0..self.style.bar_height {
+                    if x < self.scale || x + self.scale >= width || y < self.scale ||
+                       y + self.scale >= self.style.bar_height {
+                        image.put_pixel(x, y, border);
+                    }
+                }
+            }
+        }
+
+        Ok(image)
+    }
+
+    // Writes a click event for the item below the pointer to the program
+    fn send_click(&self, mouse_event: &mouse::MouseEvent) -> Result<(), Box<error::Error>> {
+        let button = match (mouse_event.scroll, mouse_event.state, mouse_event.button) {
+            (Some(direction), _, _) => mouse::scroll_number(direction),
+            (None, Some(mouse::ButtonState::PRESSED), Some(button)) => mouse::button_number(button),
+            _ => return Ok(()),
+        };
+
+        let x = mouse_event.x as u32;
+        let rect = self.item_rects
+            .iter()
+            .find(|&&(item_x, width, _)| x >= item_x && x < item_x + width);
+        let (item_x, width, index) = match rect {
+            Some(&rect) => rect,
+            None => return Ok(()),
+        };
+
+        let mut stream = self.stream.lock().map_err(|e| e.to_string())?;
+        let state = &mut stream.state;
+        if !state.click_events {
+            return Ok(());
+        }
+
+        let event = {
+            let item = match state.items.get(index) {
+                Some(item) => item,
+                None => return Ok(()),
+            };
+            // Like i3bar, `x` and `y` are absolute and all values are in logical pixels
+            json!({
+                "name": item.name,
+                "instance": item.instance,
+                "button": button,
+                "x": mouse_event.root_x as i32,
+                "y": mouse_event.root_y as i32,
+                "relative_x": (x - item_x) / self.scale,
+                "relative_y": mouse_event.y as u32 / self.scale,
+                "width": width / self.scale,
+                "height": self.style.bar_height / self.scale,
+            })
+        };
+
+        let prefix = if state.clicks_sent { "," } else { "[\n" };
+        state.clicks_sent = true;
+        if let Some(ref mut click_out) = state.click_out {
+            write!(click_out, "{}{}\n", prefix, event)?;
+            click_out.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl modules::Block for I3barBlock {
+    fn start_interval(&mut self,
+                      interval_out: mpsc::Sender<(Option<u32>, Option<mouse::MouseEvent>)>) {
+        modules::start_line_stream(&self.stream, &self.process_group, interval_out);
+    }
+
+    fn mouse_event(&mut self, mouse_event: Option<mouse::MouseEvent>) -> bool {
+        if let Some(ref mouse_event) = mouse_event {
+            let _ = self.send_click(mouse_event);
+        }
+
+        false
+    }
+
+    fn render(&mut self) -> Result<image::DynamicImage, Box<error::Error>> {
+        let (generation, items) = {
+            let stream = self.stream.lock().map_err(|e| e.to_string())?;
+            (stream.generation, stream.state.items.clone())
+        };
+
+        if let Some((cache_generation, ref cache)) = self.cache {
+            if cache_generation == generation {
+                return Ok(cache.clone());
+            }
+        }
+
+        // Items without text are not displayed
+        let mut images = Vec::new();
+        for (index, item) in items.iter().enumerate() {
+            if !item.full_text.is_empty() {
+                images.push((index, self.render_item(item)?));
+            }
+        }
+
+        // Every item except the last one is followed by the separator block
+        let mut width = 0;
+        for (i, &(index, ref image)) in images.iter().enumerate() {
+            width += image.width();
+            if i + 1 < images.len() {
+                width += items[index].separator_block_width * self.scale;
+            }
+        }
+
+        let mut image = image::DynamicImage::new_rgba8(width, self.style.bar_height);
+        fill(&mut image, &self.bg_col, 0, width);
+
+        self.item_rects.clear();
+        let mut x = 0;
+        for (i, &(index, ref item_image)) in images.iter().enumerate() {
+            for item_x in 0..item_image.width() {
+                for y in 0..item_image.height() {
+                    image.put_pixel(x + item_x, y, item_image.get_pixel(item_x, y));
+                }
+            }
+            self.item_rects.push((x, item_image.width(), index));
+            x += item_image.width();
+
+            if i + 1 < images.len() {
+                let separator_width = items[index].separator_block_width * self.scale;
+                if items[index].separator && separator_width > 0 {
+                    let separator_col = image::Rgba { data: SEPARATOR_COL };
+                    fill(&mut image, &color_image(separator_col), x + separator_width / 2, 1);
+                }
+                x += separator_width;
+            }
+        }
+
+        self.cache = Some((generation, image.clone()));
+        Ok(image)
+    }
+}
+
+// Blocks reading stdin share its stream, only a block's own command is killed
+impl Drop for I3barBlock {
+    fn drop(&mut self) {
+        self.process_group.stop();
+    }
+}
+
+// Parses the header or a status line, without the brackets and commas of the infinite array
+fn parse_line(line: &str) -> Option<serde_json::Value> {
+    let mut line = line.trim().trim_left_matches(',').trim_right_matches(',').trim();
+    if line.starts_with("[[") {
+        line = &line[1..];
+    }
+
+    if line.is_empty() || line == "[" {
+        return None;
+    }
+
+    serde_json::from_str(line).ok()
+}
+
+fn item_from_json(value: &serde_json::Value) -> Option<Item> {
+    let string = |name: &str| value.get(name).and_then(|v| v.as_str()).map(|v| v.to_owned());
+    let color = |name: &str| string(name).and_then(|color| parse_input::parse_color(&color));
+    let boolean = |name: &str, default| {
+        value.get(name).and_then(|v| v.as_bool()).unwrap_or(default)
+    };
+
+    // Status objects without text are invalid
+    let full_text = match string("full_text") {
+        Some(full_text) => full_text,
+        None => return None,
+    };
+
+    let min_width = match value.get("min_width") {
+        Some(&serde_json::Value::String(ref text)) => Some(MinWidth::Text(text.clone())),
+        Some(min_width) => min_width.as_u64().map(|width| MinWidth::Pixels(width as u32)),
+        None => None,
+    };
+
+    Some(Item {
+        full_text: full_text,
+        name: string("name"),
+        instance: string("instance"),
+        color: color("color"),
+        background: color("background"),
+        border: color("border"),
+        min_width: min_width,
+        align: string("align").unwrap_or_else(|| "left".to_owned()),
+        separator: boolean("separator", true),
+        separator_block_width: value.get("separator_block_width")
+            .and_then(|v| v.as_u64())
+            .map(|width| width as u32)
+            .unwrap_or(SEPARATOR_BLOCK_WIDTH),
+        urgent: boolean("urgent", false),
+        markup: string("markup").map_or(false, |markup| markup == "pango"),
+    })
+}
+
+fn color_image(col: image::Rgba<u8>) -> image::DynamicImage {
+    let mut image = image::DynamicImage::new_rgba8(1, 1);
+    image.put_pixel(0, 0, col);
+    image
+}
+
+// Tiles the background over the full height of the columns starting at `x`
+fn fill(image: &mut image::DynamicImage, bg_col: &image::DynamicImage, x: u32, width: u32) {
+    for x in x..x + width {
+        for y in 0..image.height() {
+            let pixel = bg_col.get_pixel(x % bg_col.width(), y % bg_col.height());
+            image.put_pixel(x, y, pixel);
+        }
+    }
+}
+
+#[test]
+fn parse_line_strips_infinite_array() {
+    assert!(parse_line("[").is_none());
+    assert!(parse_line("{\"version\":1,\"click_events\":true}").unwrap().get("version").is_some());
+
+    let first = parse_line("[[{\"full_text\":\"a\"}],").unwrap();
+    let second = parse_line(",[{\"full_text\":\"b\",\"min_width\":\"bb\",\"urgent\":true}]")
+        .unwrap();
+
+    let first = item_from_json(&first.as_array().unwrap()[0]).unwrap();
+    assert_eq!(first.full_text, "a");
+    assert_eq!(first.separator, true);
+    assert_eq!(first.align, "left");
+
+    let second = item_from_json(&second.as_array().unwrap()[0]).unwrap();
+    assert_eq!(second.min_width, Some(MinWidth::Text("bb".to_owned())));
+    assert_eq!(second.urgent, true);
+}
@@ -0,0 +1,376 @@
+use std::mem;
+use std::error;
+use std::sync::{self, mpsc};
+use image::{self, GenericImage};
+
+use font;
+use mouse;
+use modules;
+use parse_input;
+use modules::text;
+
+lazy_static! {
+    // Lines from stdin, shared by the blocks of all bars
+    static ref STDIN_STREAM: sync::Arc<sync::Mutex<modules::LineStream<State>>> =
+        modules::LineStream::from_stdin(State::new());
+}
+
+// Displays one side of lines in the lemonbar format
+// The lines are read from the output of `command`, or from stdin without it
+pub struct LemonbarBlock {
+    bar_height: u32,
+    font_height: u32,
+    font: Vec<font::Font>,
+    bg_col: image::DynamicImage,
+    fg_col: image::Rgba<u8>,
+    scale: u32,
+    // Index of the segments displayed by this block, 0 is left, 1 center and 2 right
+    side: usize,
+    // Shared by the three sides, the blocks of a bar are always dropped together
+    process_group: modules::ProcessGroup,
+    stream: sync::Arc<sync::Mutex<modules::LineStream<State>>>,
+    // Image and the generation of the line it was rendered from
+    cache: Option<(u64, image::DynamicImage)>,
+    // Position, width and actions of every segment in the last rendered image
+    segment_rects: Vec<(u32, u32, Vec<(u32, String)>)>,
+}
+
+// Latest line of a lemonbar stream, shared by the blocks of all sides
+struct State {
+    sides: Vec<Vec<Segment>>,
+}
+
+impl State {
+    fn new() -> State {
+        State { sides: vec![Vec::new(), Vec::new(), Vec::new()] }
+    }
+}
+
+impl modules::LineProtocol for State {
+    // Every line replaces all segments
+    fn handle_line(&mut self, line: &str) -> bool {
+        self.sides = parse_line(line);
+        true
+    }
+}
+
+// Text with the same attributes
+#[derive(Clone, Debug, PartialEq)]
+struct Segment {
+    text: String,
+    fg: Option<image::Rgba<u8>>,
+    bg: Option<image::Rgba<u8>>,
+    underline: bool,
+    // The foreground color is used for underlines without color
+    underline_col: Option<image::Rgba<u8>>,
+    // Button and command of all clickable areas containing the text, innermost last
+    actions: Vec<(u32, String)>,
+}
+
+impl Segment {
+    fn new() -> Segment {
+        Segment {
+            text: String::new(),
+            fg: None,
+            bg: None,
+            underline: false,
+            underline_col: None,
+            actions: Vec::new(),
+        }
+    }
+}
+
+// Creates the left, center and right block, which replace the blocks from the config
+pub fn create_blocks(config: &parse_input::Config,
+                     command: Option<String>)
+                     -> Result<Vec<Box<modules::Block>>, Box<error::Error>> {
+    let font = config.font.clone().ok_or("rusttype::Font required in [general].")?;
+    let font_height = config.font_height.ok_or("rusttype::Font Height required in [general].")?;
+
+    let stream = match command {
+        Some(command) => modules::LineStream::new(Some(command), State::new()),
+        None => STDIN_STREAM.clone(),
+    };
+    let process_group = modules::ProcessGroup::new();
+
+    let mut blocks: Vec<Box<modules::Block>> = Vec::new();
+    for side in 0..3 {
+        blocks.push(Box::new(LemonbarBlock {
+            bar_height: config.bar_height,
+            font_height: font_height,
+            font: font.clone(),
+            bg_col: config.bg.clone(),
+            fg_col: config.fg,
+            scale: config.scale,
+            side: side,
+            process_group: process_group.clone(),
+            stream: stream.clone(),
+            cache: None,
+            segment_rects: Vec::new(),
+        }));
+    }
+
+    Ok(blocks)
+}
+
+impl LemonbarBlock {
+    fn render_segment(&self, segment: &Segment) -> image::DynamicImage {
+        let fg_col = segment.fg.unwrap_or(self.fg_col);
+        let bg_col = match segment.bg {
+            Some(bg) => {
+                let mut bg_col = image::DynamicImage::new_rgba8(1, 1);
+                bg_col.put_pixel(0, 0, bg);
+                bg_col
+            }
+            None => self.bg_col.clone(),
+        };
+
+        // Whitespace is part of the width, lemonbar lines use it for padding
+        let glyphs = text::layout_glyphs(&self.font, self.font_height, &segment.text);
+        let width = text::advance_width(&glyphs);
+
+        let mut image = image::DynamicImage::new_rgba8(width, self.bar_height);
+        for x in 0..width {
+            for y in 0..self.bar_height {
+                let pixel = bg_col.get_pixel(x % bg_col.width(), y % bg_col.height());
+                image.put_pixel(x, y, pixel);
+            }
+        }
+
+        let y_offset = self.bar_height.saturating_sub(self.font_height) / 2;
+        text::draw_glyphs(&mut image, &glyphs, fg_col, 0, y_offset);
+
+        if segment.underline {
+            let underline_col = segment.underline_col.unwrap_or(fg_col);
+            for x in 0..width {
+                for y in self.bar_height.saturating_sub(self.scale)..self.bar_height {
+                    image.put_pixel(x, y, underline_col);
+                }
+            }
+        }
+
+        image
+    }
+}
+
+impl modules::Block for LemonbarBlock {
+    fn start_interval(&mut self,
+                      interval_out: mpsc::Sender<(Option<u32>, Option<mouse::MouseEvent>)>) {
+        modules::start_line_stream(&self.stream, &self.process_group, interval_out);
+    }
+
+    fn mouse_event(&mut self, mouse_event: Option<mouse::MouseEvent>) -> bool {
+        let mouse_event = match mouse_event {
+            Some(mouse_event) => mouse_event,
+            None => return false,
+        };
+
+        // Lemonbar triggers actions on button press
+        let button = match (mouse_event.scroll, mouse_event.state, mouse_event.button) {
+            (Some(direction), _, _) => mouse::scroll_number(direction),
+            (None, Some(mouse::ButtonState::PRESSED), Some(button)) => mouse::button_number(button),
+            _ => return false,
+        };
+
+        let x = mouse_event.x as u32;
+        let action = self.segment_rects
+            .iter()
+            .find(|&&(segment_x, width, _)| x >= segment_x && x < segment_x + width)
+            .and_then(|&(_, _, ref actions)| actions.iter().rev().find(|&&(b, _)| b == button));
+
+        if let Some(&(_, ref command)) = action {
+            let has_command = match self.stream.lock() {
+                Ok(stream) => stream.command.is_some(),
+                Err(_) => false,
+            };
+
+            // Without command the actions are printed like lemonbar does, to be piped into a shell
+            if has_command {
+                let _ = modules::shell_command(command).spawn();
+            } else {
+                println!("{}", command);
+            }
+        }
+
+        false
+    }
+
+    fn render(&mut self) -> Result<image::DynamicImage, Box<error::Error>> {
+        let (generation, segments) = {
+            let stream = self.stream.lock().map_err(|e| e.to_string())?;
+            (stream.generation, stream.state.sides[self.side].clone())
+        };
+
+        if let Some((cache_generation, ref cache)) = self.cache {
+            if cache_generation == generation {
+                return Ok(cache.clone());
+            }
+        }
+
+        let images: Vec<image::DynamicImage> =
+            segments.iter().map(|segment| self.render_segment(segment)).collect();
+        let width = images.iter().map(|image| image.width()).sum();
+
+        let mut image = image::DynamicImage::new_rgba8(width, self.bar_height);
+        self.segment_rects.clear();
+        let mut x = 0;
+        for (segment, segment_image) in segments.into_iter().zip(images.iter()) {
+            for segment_x in 0..segment_image.width() {
+                for y in 0..segment_image.height() {
+                    image.put_pixel(x + segment_x, y, segment_image.get_pixel(segment_x, y));
+                }
+            }
+            self.segment_rects.push((x, segment_image.width(), segment.actions));
+            x += segment_image.width();
+        }
+
+        self.cache = Some((generation, image.clone()));
+        Ok(image)
+    }
+}
+
+impl Drop for LemonbarBlock {
+    fn drop(&mut self) {
+        self.process_group.stop();
+    }
+}
+
+// Splits a line into the segments of the left, center and right side
+fn parse_line(line: &str) -> Vec<Vec<Segment>> {
+    let mut sides = vec![Vec::new(), Vec::new(), Vec::new()];
+    let mut side = 0;
+    let mut current = Segment::new();
+
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '%' || chars.get(i + 1) != Some(&'{') {
+            current.text.push(chars[i]);
+            i += 1;
+            continue;
+        }
+
+        // Attributes only apply to the text following them
+        if !current.text.is_empty() {
+            sides[side].push(current.clone());
+            current.text.clear();
+        }
+
+        // Every tag can contain multiple commands separated by spaces
+        i += 2;
+        while i < chars.len() && chars[i] != '}' {
+            let command = chars[i];
+            i += 1;
+
+            match command {
+                'l' => side = 0,
+                'c' => side = 1,
+                'r' => side = 2,
+                'R' => mem::swap(&mut current.fg, &mut current.bg),
+                'F' => current.fg = lemonbar_color(&read_token(&chars, &mut i)),
+                'B' => current.bg = lemonbar_color(&read_token(&chars, &mut i)),
+                'U' => current.underline_col = lemonbar_color(&read_token(&chars, &mut i)),
+                '+' | '-' => {
+                    if read_token(&chars, &mut i) == "u" {
+                        current.underline = command == '+';
+                    }
+                }
+                'A' => {
+                    let button = read_number(&chars, &mut i).unwrap_or(1);
+                    if chars.get(i) == Some(&':') {
+                        i += 1;
+                        let action = read_action(&chars, &mut i);
+                        current.actions.push((button, action));
+                    } else {
+                        current.actions.pop();
+                    }
+                }
+                ' ' => (),
+                _ => {
+                    read_token(&chars, &mut i);
+                }
+            }
+        }
+        i += 1;
+    }
+
+    if !current.text.is_empty() {
+        sides[side].push(current);
+    }
+
+    sides
+}
+
+// Reads until the next space or the end of the tag
+fn read_token(chars: &[char], i: &mut usize) -> String {
+    let mut token = String::new();
+    while *i < chars.len() && chars[*i] != ' ' && chars[*i] != '}' {
+        token.push(chars[*i]);
+        *i += 1;
+    }
+    token
+}
+
+fn read_number(chars: &[char], i: &mut usize) -> Option<u32> {
+    let mut number = String::new();
+    while *i < chars.len() && chars[*i].is_digit(10) {
+        number.push(chars[*i]);
+        *i += 1;
+    }
+    number.parse().ok()
+}
+
+// Reads the command of an action up to the closing `:`, colons can be escaped with `\:`
+fn read_action(chars: &[char], i: &mut usize) -> String {
+    let mut action = String::new();
+    while *i < chars.len() && chars[*i] != ':' {
+        if chars[*i] == '\\' && chars.get(*i + 1) == Some(&':') {
+            *i += 1;
+        }
+        action.push(chars[*i]);
+        *i += 1;
+    }
+    *i += 1;
+    action
+}
+
+// Lemonbar colors are `#RGB`, `#RRGGBB` or `#AARRGGBB`, `-` resets the color
+fn lemonbar_color(token: &str) -> Option<image::Rgba<u8>> {
+    if !token.starts_with('#') || !token[1..].chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+
+    match token.len() {
+        4 => {
+            let rgb: String = token[1..].chars().flat_map(|c| vec![c, c]).collect();
+            parse_input::parse_color(&format!("#{}", rgb))
+        }
+        9 => parse_input::parse_color(&format!("#{}{}", &token[3..], &token[1..3])),
+        _ => parse_input::parse_color(token),
+    }
+}
+
+#[test]
+fn parse_line_splits_sides_and_attributes() {
+    let sides = parse_line("%{l}a%{F#ff0000 +u}b%{F-}%{c}%{A3:echo x\\:y:}c%{A}%{r}d");
+
+    assert_eq!(sides[0].len(), 2);
+    assert_eq!(sides[0][0].text, "a");
+    assert_eq!(sides[0][1].text, "b");
+    assert_eq!(sides[0][1].fg, Some(image::Rgba { data: [255, 0, 0, 255] }));
+    assert!(sides[0][1].underline);
+
+    assert_eq!(sides[1].len(), 1);
+    assert_eq!(sides[1][0].actions, vec![(3, "echo x:y".to_owned())]);
+    assert_eq!(sides[1][0].fg, None);
+
+    assert_eq!(sides[2][0].text, "d");
+    assert!(sides[2][0].actions.is_empty());
+}
+
+#[test]
+fn lemonbar_color_supports_short_and_alpha_colors() {
+    assert_eq!(lemonbar_color("#f00"), Some(image::Rgba { data: [255, 0, 0, 255] }));
+    assert_eq!(lemonbar_color("#80ff0000"), Some(image::Rgba { data: [255, 0, 0, 128] }));
+    assert_eq!(lemonbar_color("-"), None);
+}
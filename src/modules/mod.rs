@@ -1,13 +1,22 @@
 use toml;
+use libc;
 use image;
+use std::io;
 use std::error;
-use std::sync::mpsc;
+use std::thread;
+use std::process;
+use std::io::BufRead;
+use std::sync::{self, mpsc};
 use std::collections;
+use std::os::unix::process::CommandExt;
 
 use mouse;
 use parse_input;
 
 mod text;
+mod i3bar;
+pub mod lemonbar;
+mod stream;
 mod command;
 
 lazy_static! {
@@ -16,6 +25,8 @@ lazy_static! {
             , fn(parse_input::Config, &toml::Value) -> Result<Box<Block>, Box<error::Error>>> = collections::HashMap::new();
         m.insert("text", text::TextBlock::create);
         m.insert("command", command::CommandBlock::create);
+        m.insert("stream", stream::StreamBlock::create);
+        m.insert("i3bar", i3bar::I3barBlock::create);
         m
     };
 }
@@ -27,7 +38,209 @@ pub trait Block: Send + 'static {
     // Used to render the settings into an Image Block
     fn render(&mut self) -> Result<image::DynamicImage, Box<error::Error>>;
 
+    // Used instead of `render` when the bar is too small for all blocks
+    // Returns None if the block has no shorter version
+    fn render_short(&mut self) -> Option<Result<image::DynamicImage, Box<error::Error>>> {
+        None
+    }
+
     // Used to update the settings based on mouse focus
     // Return true if it requires redraw
     fn mouse_event(&mut self, Option<mouse::MouseEvent>) -> bool;
 }
+
+// Creates a `sh -c` command running in its own process group
+// This allows killing pipelines with all their processes using `kill_process_group`
+pub fn shell_command(command: &str) -> process::Command {
+    let mut shell_command = process::Command::new("sh");
+    shell_command.arg("-c").arg(command).before_exec(|| {
+        unsafe { libc::setpgid(0, 0) };
+        Ok(())
+    });
+    shell_command
+}
+
+pub fn kill_process_group(child: &mut process::Child) {
+    kill_process_group_id(child.id());
+    let _ = child.wait();
+}
+
+// Kills the group without waiting, for children which are waited for on another thread
+pub fn kill_process_group_id(id: u32) {
+    unsafe { libc::kill(-(id as libc::pid_t), libc::SIGKILL) };
+}
+
+// Process group of a long-running command, shared between a block and its reading thread
+// Once stopped, the running process group is killed and no new one can be started
+#[derive(Clone)]
+pub struct ProcessGroup {
+    // Stopped flag and the id of the running group
+    state: sync::Arc<sync::Mutex<(bool, Option<u32>)>>,
+}
+
+impl ProcessGroup {
+    pub fn new() -> ProcessGroup {
+        ProcessGroup { state: sync::Arc::new(sync::Mutex::new((false, None))) }
+    }
+
+    // Returns false and kills the group of the child if it has been stopped already
+    pub fn start(&self, child: &mut process::Child) -> bool {
+        let stopped = match self.state.lock() {
+            Ok(mut state) => {
+                state.1 = Some(child.id());
+                state.0
+            }
+            Err(_) => true,
+        };
+
+        if stopped {
+            kill_process_group(child);
+        }
+        !stopped
+    }
+
+    // Kills and reaps the group of a child started with `start`
+    pub fn finish(&self, child: &mut process::Child) {
+        if let Ok(mut state) = self.state.lock() {
+            state.1 = None;
+        }
+        kill_process_group(child);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.state.lock().map(|state| state.0).unwrap_or(true)
+    }
+
+    // The reading thread still reaps the killed process once its output has been closed
+    pub fn stop(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.0 = true;
+            if let Some(pid) = state.1.take() {
+                unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+            }
+        }
+    }
+}
+
+// Protocol of a program writing status lines, like i3bar or lemonbar
+pub trait LineProtocol {
+    // Updates the state with a new line, returns true if the blocks have to be rendered again
+    fn handle_line(&mut self, line: &str) -> bool;
+
+    // Receives the stdin of a started command, for sending events to the program
+    fn set_input(&mut self, _input: process::ChildStdin) {}
+}
+
+// Latest state of a line protocol stream, shared by all blocks displaying it
+pub struct LineStream<T> {
+    pub state: T,
+    // Incremented with every line which changed the state
+    pub generation: u64,
+    // Started by the first block, without command the lines are read from stdin
+    pub command: Option<String>,
+    started: bool,
+    redraw_outs: Vec<mpsc::Sender<(Option<u32>, Option<mouse::MouseEvent>)>>,
+}
+
+impl<T: LineProtocol + Send + 'static> LineStream<T> {
+    pub fn new(command: Option<String>, state: T) -> sync::Arc<sync::Mutex<LineStream<T>>> {
+        sync::Arc::new(sync::Mutex::new(LineStream {
+            state: state,
+            generation: 0,
+            command: command,
+            started: false,
+            redraw_outs: Vec::new(),
+        }))
+    }
+
+    // Lines from stdin are read for the whole runtime, since stdin can only be read once
+    pub fn from_stdin(state: T) -> sync::Arc<sync::Mutex<LineStream<T>>> {
+        let stream = LineStream::new(None, state);
+
+        let reader_stream = stream.clone();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            read_lines(stdin.lock(), &reader_stream, false);
+        });
+
+        stream
+    }
+}
+
+// Registers a block for redraws and starts the command of the stream with the first block
+pub fn start_line_stream<T>(stream: &sync::Arc<sync::Mutex<LineStream<T>>>,
+                            process_group: &ProcessGroup,
+                            redraw_out: mpsc::Sender<(Option<u32>, Option<mouse::MouseEvent>)>)
+    where T: LineProtocol + Send + 'static
+{
+    let command = match stream.lock() {
+        Ok(mut stream) => {
+            stream.redraw_outs.push(redraw_out);
+            if stream.started {
+                None
+            } else {
+                stream.started = true;
+                stream.command.clone()
+            }
+        }
+        Err(_) => None,
+    };
+
+    if let Some(command) = command {
+        let process_group = process_group.clone();
+        let stream = stream.clone();
+        thread::spawn(move || {
+            let _ = run_line_command(&command, &process_group, &stream);
+        });
+    }
+}
+
+fn run_line_command<T: LineProtocol>(command: &str,
+                                     process_group: &ProcessGroup,
+                                     stream: &sync::Mutex<LineStream<T>>)
+                                     -> Result<(), Box<error::Error>> {
+    let mut child = shell_command(command)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()?;
+    if !process_group.start(&mut child) {
+        return Ok(());
+    }
+    let stdout = io::BufReader::new(child.stdout.take().ok_or("Stdout not piped.")?);
+
+    if let Some(stdin) = child.stdin.take() {
+        stream.lock().map_err(|e| e.to_string())?.state.set_input(stdin);
+    }
+
+    read_lines(stdout, stream, true);
+    process_group.finish(&mut child);
+
+    Ok(())
+}
+
+// Passes every line to the protocol and notifies the blocks if it changed the state
+// When `stop_unused` is set this returns once no bar is interested in updates anymore
+fn read_lines<R: BufRead, T: LineProtocol>(reader: R,
+                                           stream: &sync::Mutex<LineStream<T>>,
+                                           stop_unused: bool) {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        let mut stream = match stream.lock() {
+            Ok(stream) => stream,
+            Err(_) => break,
+        };
+        if !stream.state.handle_line(&line) {
+            continue;
+        }
+        stream.generation += 1;
+
+        stream.redraw_outs.retain(|redraw_out| redraw_out.send((None, None)).is_ok());
+        if stop_unused && stream.redraw_outs.is_empty() {
+            break;
+        }
+    }
+}
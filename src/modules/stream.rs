@@ -0,0 +1,179 @@
+use toml;
+use std::cmp;
+use std::time;
+use std::error;
+use std::thread;
+use std::process;
+use std::sync::{self, mpsc};
+use std::io::{self, BufRead};
+use image::{self, GenericImage};
+
+use mouse;
+use modules;
+use parse_input;
+use modules::text;
+
+// Delay before restarting a command which has exited, doubled after every restart
+const RESTART_MIN_MS: u64 = 500;
+const RESTART_MAX_MS: u64 = 30_000;
+
+pub struct StreamBlock {
+    style: text::TextStyle,
+    bg_col: image::DynamicImage,
+    fg_col: image::Rgba<u8>,
+    command: String,
+    process_group: modules::ProcessGroup,
+    // Last line printed by the command
+    text: sync::Arc<sync::Mutex<String>>,
+    cache: sync::Arc<sync::Mutex<Option<image::DynamicImage>>>,
+    hover_bg_col: image::DynamicImage,
+    hover_fg_col: image::Rgba<u8>,
+    mouse_commands: mouse::MouseCommands,
+    name: String,
+    // Width of the last rendered image, passed to mouse commands
+    rendered_width: u32,
+    hover: bool,
+}
+
+// Unwraps cannot fail
+impl StreamBlock {
+    pub fn create(config: parse_input::Config,
+                  value: &toml::Value)
+                  -> Result<Box<modules::Block>, Box<error::Error>> {
+        let command = value.lookup("command").ok_or("Could not find command in a stream module.")?;
+        let command = command.as_str().ok_or("Command in stream module is not a String.")?;
+
+        // Read mouse values from toml
+        let mut hover_bg_col = config.bg.clone();
+        let mut hover_fg_col = config.fg;
+
+        if let Some(hover_table) = value.lookup("mouse") {
+            hover_bg_col = parse_input::toml_value_to_image(hover_table, "hover_bg")
+                .unwrap_or(hover_bg_col);
+            hover_fg_col = parse_input::toml_value_to_rgba(hover_table, "hover_fg")
+                .unwrap_or(hover_fg_col);
+        }
+
+        Ok(Box::new(StreamBlock {
+            style: text::TextStyle::from_config(&config),
+            bg_col: config.bg,
+            fg_col: config.fg,
+            command: command.to_owned(),
+            process_group: modules::ProcessGroup::new(),
+            text: sync::Arc::new(sync::Mutex::new(String::new())),
+            cache: sync::Arc::new(sync::Mutex::new(None)),
+            hover_bg_col: hover_bg_col,
+            hover_fg_col: hover_fg_col,
+            mouse_commands: mouse::MouseCommands::from_toml(value.lookup("mouse")),
+            name: config.name,
+            rendered_width: 0,
+            hover: false,
+        }))
+    }
+}
+
+impl modules::Block for StreamBlock {
+    fn start_interval(&mut self,
+                      interval_out: mpsc::Sender<(Option<u32>, Option<mouse::MouseEvent>)>) {
+        let command = self.command.clone();
+        let process_group = self.process_group.clone();
+        let text = self.text.clone();
+        let cache = self.cache.clone();
+        thread::spawn(move || {
+            let mut restart_delay = RESTART_MIN_MS;
+            loop {
+                let start = time::Instant::now();
+                let running =
+                    stream_command(&command, &process_group, &text, &cache, &interval_out);
+                if process_group.is_stopped() || running.ok() == Some(false) {
+                    break;
+                }
+
+                // Commands which ran for a while start over with a short delay
+                if start.elapsed() > time::Duration::from_millis(RESTART_MAX_MS) {
+                    restart_delay = RESTART_MIN_MS;
+                }
+                thread::sleep(time::Duration::from_millis(restart_delay));
+                restart_delay = cmp::min(restart_delay * 2, RESTART_MAX_MS);
+            }
+        });
+    }
+
+    fn mouse_event(&mut self, mouse_event: Option<mouse::MouseEvent>) -> bool {
+        if let Some(ref mouse_event) = mouse_event {
+            self.mouse_commands
+                .spawn(mouse_event, &self.name, self.rendered_width, self.style.bar_height);
+        }
+
+        if self.hover != mouse_event.is_some() {
+            self.hover = mouse_event.is_some();
+            let mut cache_lock = self.cache.lock().unwrap(); // TODO: Not unwrap?
+            *cache_lock = None;
+            return true;
+        }
+
+        false
+    }
+
+    fn render(&mut self) -> Result<image::DynamicImage, Box<error::Error>> {
+        let mut cache = self.cache.lock().map_err(|e| e.to_string())?;
+        if let Some(ref cache) = *cache {
+            return Ok(cache.clone());
+        }
+
+        let text = self.text.lock().map_err(|e| e.to_string())?.clone();
+
+        let (fg_col, bg_col) = if self.hover {
+            (self.hover_fg_col, &self.hover_bg_col)
+        } else {
+            (self.fg_col, &self.bg_col)
+        };
+
+        let mut text_block = text::TextBlock::for_text(&self.style, text, fg_col, bg_col);
+
+        let image = text_block.render()?;
+        self.rendered_width = image.width();
+        *cache = Some(image.clone());
+        Ok(image)
+    }
+}
+
+// Bars are recreated whenever outputs change, so the command must not outlive its block
+impl Drop for StreamBlock {
+    fn drop(&mut self) {
+        self.process_group.stop();
+    }
+}
+
+// Updates the text with every line the command prints until it exits
+// Returns false and kills the command once the bar doesn't accept updates anymore
+fn stream_command(command: &str,
+                  process_group: &modules::ProcessGroup,
+                  text: &sync::Mutex<String>,
+                  cache: &sync::Mutex<Option<image::DynamicImage>>,
+                  interval_out: &mpsc::Sender<(Option<u32>, Option<mouse::MouseEvent>)>)
+                  -> Result<bool, Box<error::Error>> {
+    let mut child = modules::shell_command(command).stdout(process::Stdio::piped()).spawn()?;
+    if !process_group.start(&mut child) {
+        return Ok(false);
+    }
+    let stdout = io::BufReader::new(child.stdout.take().ok_or("Stdout not piped.")?);
+
+    for line in stdout.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        *text.lock().map_err(|e| e.to_string())? = line;
+        *cache.lock().map_err(|e| e.to_string())? = None;
+
+        if interval_out.send((None, None)).is_err() {
+            process_group.finish(&mut child);
+            return Ok(false);
+        }
+    }
+
+    // Stdout has been closed, make sure nothing of the old command is left before restarting
+    process_group.finish(&mut child);
+    Ok(true)
+}
@@ -2,18 +2,24 @@ use toml;
 use std::cmp;
 use rusttype;
 use std::error;
-use std::process;
 use std::sync::mpsc;
 use image::{self, GenericImage, Pixel};
 
+use font;
 use mouse;
 use modules;
+use shaping;
 use parse_input;
 
 pub struct TextBlock {
     pub bar_height: u32,
     pub font_height: u32,
-    pub font: rusttype::Font<'static>,
+    // Glyphs missing in a font are taken from the next font in the list
+    pub font: Vec<font::Font>,
+    pub bold_font: Option<font::Font>,
+    pub italic_font: Option<font::Font>,
+    // Parse the text for `<span>` and `%{F}` tags
+    pub markup: bool,
     pub bg_col: image::DynamicImage,
     pub fg_col: image::Rgba<u8>,
     pub text: String,
@@ -22,12 +28,72 @@ pub struct TextBlock {
     pub cache: Option<image::DynamicImage>,
     pub hover_bg_col: image::DynamicImage,
     pub hover_fg_col: image::Rgba<u8>,
-    pub click_command: Option<String>,
+    pub mouse_commands: mouse::MouseCommands,
+    pub name: String,
+    // Width of the last rendered image, passed to mouse commands
+    pub rendered_width: u32,
     pub hover: bool,
 }
 
+// Font settings of blocks which render their text with a TextBlock
+#[derive(Clone)]
+pub struct TextStyle {
+    pub bar_height: u32,
+    pub font_height: u32,
+    pub font: Vec<font::Font>,
+    pub bold_font: Option<font::Font>,
+    pub italic_font: Option<font::Font>,
+    pub markup: bool,
+    pub width: u32,
+    pub spacing: u32,
+}
+
+impl TextStyle {
+    // Unwraps cannot fail
+    pub fn from_config(config: &parse_input::Config) -> TextStyle {
+        TextStyle {
+            bar_height: config.bar_height,
+            font_height: cmp::min(config.bar_height, config.font_height.unwrap()),
+            font: config.font.clone().unwrap(),
+            bold_font: config.bold_font.clone(),
+            italic_font: config.italic_font.clone(),
+            markup: config.markup,
+            width: config.width,
+            spacing: config.spacing,
+        }
+    }
+}
+
 // Unwraps cannot fail
 impl TextBlock {
+    // Block without mouse commands, used to render the text of other blocks
+    pub fn for_text(style: &TextStyle,
+                    text: String,
+                    fg_col: image::Rgba<u8>,
+                    bg_col: &image::DynamicImage)
+                    -> TextBlock {
+        TextBlock {
+            bar_height: style.bar_height,
+            font_height: style.font_height,
+            font: style.font.clone(),
+            bold_font: style.bold_font.clone(),
+            italic_font: style.italic_font.clone(),
+            markup: style.markup,
+            bg_col: bg_col.clone(),
+            fg_col: fg_col,
+            text: text,
+            width: style.width,
+            spacing: style.spacing,
+            cache: None,
+            hover_bg_col: bg_col.clone(),
+            hover_fg_col: fg_col,
+            mouse_commands: mouse::MouseCommands::from_toml(None),
+            name: String::new(),
+            rendered_width: 0,
+            hover: false,
+        }
+    }
+
     pub fn create(config: parse_input::Config,
                   value: &toml::Value)
                   -> Result<Box<modules::Block>, Box<error::Error>> {
@@ -38,20 +104,21 @@ impl TextBlock {
         // Read mouse values from toml
         let mut hover_bg_col = config.bg.clone();
         let mut hover_fg_col = config.fg;
-        let mut click_command = None;
 
         if let Some(hover_table) = value.lookup("mouse") {
             hover_bg_col = parse_input::toml_value_to_image(hover_table, "hover_bg")
                 .unwrap_or(hover_bg_col);
             hover_fg_col = parse_input::toml_value_to_rgba(hover_table, "hover_fg")
                 .unwrap_or(hover_fg_col);
-            click_command = parse_input::toml_value_to_string(hover_table, "command").ok();
         }
 
         Ok(Box::new(TextBlock {
             bar_height: config.bar_height,
             font_height: font_height,
             font: config.font.unwrap(),
+            bold_font: config.bold_font,
+            italic_font: config.italic_font,
+            markup: config.markup,
             bg_col: config.bg,
             fg_col: config.fg,
             text: text.to_owned(),
@@ -60,7 +127,9 @@ impl TextBlock {
             cache: None,
             hover_bg_col: hover_bg_col,
             hover_fg_col: hover_fg_col,
-            click_command: click_command,
+            mouse_commands: mouse::MouseCommands::from_toml(value.lookup("mouse")),
+            name: config.name,
+            rendered_width: 0,
             hover: false,
         }))
     }
@@ -74,11 +143,8 @@ impl modules::Block for TextBlock {
 
     fn mouse_event(&mut self, mouse_event: Option<mouse::MouseEvent>) -> bool {
         if let Some(ref mouse_event) = mouse_event {
-            if let Some(mouse::ButtonState::RELEASED) = mouse_event.state {
-                if let Some(ref command) = self.click_command {
-                    let _ = process::Command::new("sh").arg("-c").arg(&command).spawn();
-                }
-            }
+            self.mouse_commands
+                .spawn(mouse_event, &self.name, self.rendered_width, self.bar_height);
         }
 
         if self.hover != mouse_event.is_some() {
@@ -103,20 +169,34 @@ impl modules::Block for TextBlock {
 
         let text = self.text.replace('\n', "").replace('\r', "").replace('\t', "");
 
-        let scale = rusttype::Scale {
-            x: self.font_height as f32,
-            y: self.font_height as f32,
+        let runs = if self.markup {
+            parse_markup(&text)
+        } else {
+            vec![Run::new(text)]
         };
 
-        let v_metrics = self.font.v_metrics(scale);
-        let offset = rusttype::point(0.0, v_metrics.ascent);
+        // All runs share the baseline of the first regular font
+        let baseline = self.font.first().map_or(0., |font| ascent(font, self.font_height));
+        let mut caret = 0.;
+        let mut run_glyphs = Vec::new();
+        for run in runs {
+            let mut fonts = Vec::new();
+            match (run.bold, run.italic, &self.bold_font, &self.italic_font) {
+                (true, _, &Some(ref bold_font), _) => fonts.push(bold_font),
+                (_, true, _, &Some(ref italic_font)) => fonts.push(italic_font),
+                _ => (),
+            }
+            fonts.extend(self.font.iter());
 
-        let glyphs: Vec<rusttype::PositionedGlyph> = self.font
-            .layout(&text, scale, offset)
-            .collect();
+            let (glyphs, run_end) =
+                layout_text(&fonts, self.font_height, &run.text, caret, baseline);
+            caret = run_end;
+            run_glyphs.push((glyphs, run.fg.unwrap_or(*fg_col)));
+        }
 
         // Find the most visually pleasing width to display -> No idea what's going on exactly
-        let mut width = glyphs.iter()
+        let mut width = run_glyphs.iter()
+            .flat_map(|&(ref glyphs, _)| glyphs.iter())
             .rev()
             .filter_map(|g| {
                 g.pixel_bounding_box()
@@ -144,21 +224,349 @@ impl modules::Block for TextBlock {
         }
 
         // Render glyphs on top of background
-        for glyph in glyphs {
-            if let Some(bb) = glyph.pixel_bounding_box() {
-                glyph.draw(|x, y, v| {
-                    let x = x + bb.min.x as u32;
-                    let y = y + bb.min.y as u32;
-                    let mut current_pixel = image.get_pixel(x + x_offset, y + y_offset);
-                    let mut pixel_col = *fg_col;
-                    pixel_col.data[3] = (v * 255.0) as u8;
-                    current_pixel.blend(&pixel_col);
-                    image.put_pixel(x + x_offset, y + y_offset, current_pixel);
-                });
-            }
+        for &(ref glyphs, run_fg_col) in &run_glyphs {
+            draw_glyphs(&mut image, glyphs, run_fg_col, x_offset, y_offset);
         }
 
+        self.rendered_width = width;
         self.cache = Some(image.clone());
         Ok(image)
     }
 }
+
+// Text with the same color and style
+#[derive(Clone, Debug, PartialEq)]
+pub struct Run {
+    pub text: String,
+    pub fg: Option<image::Rgba<u8>>,
+    pub bold: bool,
+    pub italic: bool,
+}
+
+impl Run {
+    fn new(text: String) -> Run {
+        Run {
+            text: text,
+            fg: None,
+            bold: false,
+            italic: false,
+        }
+    }
+}
+
+// Lays out the text on a single line, with the top of the line at y = 0
+pub fn layout_glyphs<'a>(fonts: &'a [font::Font],
+                         font_height: u32,
+                         text: &str)
+                         -> Vec<rusttype::PositionedGlyph<'a>> {
+    let fonts: Vec<&font::Font> = fonts.iter().collect();
+    let baseline = fonts.first().map_or(0., |font| ascent(font, font_height));
+    layout_text(&fonts, font_height, text, 0., baseline).0
+}
+
+// Shapes the text with every character taken from the first font containing it
+// Returns the glyphs in display order and the position after the last glyph
+fn layout_text<'a>(fonts: &[&'a font::Font],
+                   font_height: u32,
+                   text: &str,
+                   x: f32,
+                   baseline: f32)
+                   -> (Vec<rusttype::PositionedGlyph<'a>>, f32) {
+    let mut glyphs = Vec::new();
+    let mut caret = x;
+    for (rtl, bidi_run) in shaping::bidi_runs(text) {
+        // Right-to-left runs are displayed starting with their last character
+        let mut segments = split_by_font(fonts, bidi_run);
+        if rtl {
+            segments.reverse();
+        }
+
+        for (font, segment) in segments {
+            let (segment_glyphs, segment_end) =
+                shaping::shape(font, font_height, segment, rtl, caret, baseline);
+            caret = segment_end;
+            glyphs.extend(segment_glyphs);
+        }
+    }
+    (glyphs, caret)
+}
+
+// Splits the text into parts which can be rendered with a single font
+// Whitespace stays with the previous font, characters no font contains use the first one
+fn split_by_font<'a, 'b>(fonts: &[&'a font::Font],
+                         text: &'b str)
+                         -> Vec<(&'a font::Font, &'b str)> {
+    let mut segments = Vec::new();
+    let mut current: Option<(usize, usize)> = None;
+    for (index, character) in text.char_indices() {
+        let font_index = match current {
+            Some((font_index, _)) if character.is_whitespace() => font_index,
+            _ => fonts.iter().position(|font| has_glyph(font, character)).unwrap_or(0),
+        };
+
+        match current {
+            Some((current_index, start)) if current_index != font_index => {
+                segments.push((fonts[current_index], &text[start..index]));
+                current = Some((font_index, index));
+            }
+            None => current = Some((font_index, index)),
+            _ => (),
+        }
+    }
+
+    if let Some((font_index, start)) = current {
+        segments.push((fonts[font_index], &text[start..]));
+    }
+
+    segments
+}
+
+// Missing characters are mapped to the `.notdef` glyph with id 0
+fn has_glyph(font: &font::Font, character: char) -> bool {
+    font.rusttype_font
+        .glyph(rusttype::Codepoint(character as u32))
+        .map_or(false, |glyph| glyph.id().0 != 0)
+}
+
+fn ascent(font: &font::Font, font_height: u32) -> f32 {
+    let scale = rusttype::Scale {
+        x: font_height as f32,
+        y: font_height as f32,
+    };
+    font.rusttype_font.v_metrics(scale).ascent
+}
+
+// Width of the text including trailing whitespace
+pub fn advance_width(glyphs: &[rusttype::PositionedGlyph]) -> u32 {
+    glyphs.last()
+        .map(|g| g.position().x + g.unpositioned().h_metrics().advance_width)
+        .unwrap_or(0.0)
+        .ceil() as u32
+}
+
+// Splits text with Pango-like markup into runs
+// Supported are `<span>` with `fg`, `weight` and `style`, `<b>`, `<i>` and lemonbar's `%{F}`
+// Unknown tags are displayed as text
+pub fn parse_markup(text: &str) -> Vec<Run> {
+    let mut runs = Vec::new();
+    let mut styles = vec![Run::new(String::new())];
+    let mut current = Run::new(String::new());
+
+    let mut rest = text;
+    while !rest.is_empty() {
+        let tag_len = if rest.starts_with('<') {
+            rest.find('>').map(|end| end + 1)
+        } else if rest.starts_with("%{F") {
+            rest.find('}').map(|end| end + 1)
+        } else {
+            None
+        };
+
+        let mut style = styles.last().unwrap().clone();
+        let tag = tag_len.map(|tag_len| &rest[..tag_len]);
+        let known = match tag {
+            Some("<b>") => {
+                style.bold = true;
+                styles.push(style);
+                true
+            }
+            Some("<i>") => {
+                style.italic = true;
+                styles.push(style);
+                true
+            }
+            Some("</b>") | Some("</i>") | Some("</span>") => {
+                if styles.len() > 1 {
+                    styles.pop();
+                }
+                true
+            }
+            Some(tag) if tag.starts_with("<span") => {
+                for (name, value) in tag_attributes(&tag[5..tag.len() - 1]) {
+                    match (name.as_str(), value.as_str()) {
+                        ("fg", _) | ("foreground", _) | ("color", _) => {
+                            style.fg = parse_input::parse_color(&value);
+                        }
+                        ("weight", "bold") => style.bold = true,
+                        ("style", "italic") | ("style", "oblique") => style.italic = true,
+                        _ => (),
+                    }
+                }
+                styles.push(style);
+                true
+            }
+            Some(tag) if tag.starts_with("%{F") => {
+                styles.last_mut().unwrap().fg = parse_input::parse_color(&tag[3..tag.len() - 1]);
+                true
+            }
+            _ => false,
+        };
+
+        if known {
+            let tag_len = tag_len.unwrap();
+            let style = styles.last().unwrap();
+            if current.fg != style.fg || current.bold != style.bold ||
+               current.italic != style.italic {
+                if !current.text.is_empty() {
+                    runs.push(current.clone());
+                }
+                current = Run {
+                    text: String::new(),
+                    fg: style.fg,
+                    bold: style.bold,
+                    italic: style.italic,
+                };
+            }
+            rest = &rest[tag_len..];
+        } else {
+            let (chunk, len) = unescape(rest);
+            current.text.push_str(&chunk);
+            rest = &rest[len..];
+        }
+    }
+
+    if !current.text.is_empty() {
+        runs.push(current);
+    }
+
+    runs
+}
+
+// Reads `name="value"` pairs, values can be quoted with single or double quotes
+fn tag_attributes(attributes: &str) -> Vec<(String, String)> {
+    let mut result = Vec::new();
+    let mut rest = attributes.trim();
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals].trim().to_owned();
+        rest = rest[equals + 1..].trim_left();
+
+        let quote = match rest.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => quote,
+            _ => break,
+        };
+        let end = match rest[1..].find(quote) {
+            Some(end) => end + 1,
+            None => break,
+        };
+
+        result.push((name, rest[1..end].to_owned()));
+        rest = rest[end + 1..].trim_left();
+    }
+    result
+}
+
+// Decodes the escaped character at the start of the text
+// Returns the decoded text and the number of bytes it used
+fn unescape(text: &str) -> (String, usize) {
+    for &(entity, character) in &[("&lt;", "<"),
+                                  ("&gt;", ">"),
+                                  ("&amp;", "&"),
+                                  ("&quot;", "\""),
+                                  ("&apos;", "'")] {
+        if text.starts_with(entity) {
+            return (character.to_owned(), entity.len());
+        }
+    }
+
+    let character = text.chars().next().unwrap();
+    (character.to_string(), character.len_utf8())
+}
+
+// Blends the glyphs on top of the image, pixels outside of the image are skipped
+pub fn draw_glyphs(image: &mut image::DynamicImage,
+                   glyphs: &[rusttype::PositionedGlyph],
+                   fg_col: image::Rgba<u8>,
+                   x_offset: u32,
+                   y_offset: u32) {
+    for glyph in glyphs {
+        if let Some(bb) = glyph.pixel_bounding_box() {
+            glyph.draw(|x, y, v| {
+                // Glyphs can start left of or above the origin
+                let x = x as i32 + bb.min.x + x_offset as i32;
+                let y = y as i32 + bb.min.y + y_offset as i32;
+                if x < 0 || y < 0 || x as u32 >= image.width() || y as u32 >= image.height() {
+                    return;
+                }
+                let (x, y) = (x as u32, y as u32);
+
+                let mut current_pixel = image.get_pixel(x, y);
+                let mut pixel_col = fg_col;
+                pixel_col.data[3] = (v * 255.0) as u8;
+                current_pixel.blend(&pixel_col);
+                image.put_pixel(x, y, current_pixel);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_style() -> TextStyle {
+    TextStyle {
+        bar_height: 30,
+        font_height: 20,
+        font: vec![font::load_font("/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf").unwrap()],
+        bold_font: None,
+        italic_font: None,
+        markup: false,
+        width: 0,
+        spacing: 0,
+    }
+}
+
+#[test]
+fn render_ignores_control_characters() {
+    use modules::Block;
+
+    let mut bg_col = image::DynamicImage::new_rgba8(1, 1);
+    bg_col.put_pixel(0, 0, image::Rgba { data: [255, 0, 255, 255] });
+    let fg_col = image::Rgba { data: [255, 255, 255, 255] };
+    let style = test_style();
+
+    let image = TextBlock::for_text(&style, "a\nb\t".to_owned(), fg_col, &bg_col).render().unwrap();
+    let expected = TextBlock::for_text(&style, "ab".to_owned(), fg_col, &bg_col).render().unwrap();
+    assert_eq!(image.raw_pixels(), expected.raw_pixels());
+    assert_eq!(image.get_pixel(0, 0), image::Rgba { data: [255, 0, 255, 255] });
+}
+
+#[test]
+fn draw_glyphs_skips_pixels_outside_of_the_image() {
+    let style = test_style();
+    let scale = rusttype::Scale::uniform(style.font_height as f32);
+    // Full block, which starts left of and above the image
+    let glyph = style.font[0]
+        .rusttype_font
+        .glyph('\u{2588}')
+        .unwrap()
+        .scaled(scale)
+        .positioned(rusttype::point(-5., 5.));
+
+    let mut image = image::DynamicImage::new_rgba8(30, 30);
+    let fg_col = image::Rgba { data: [255, 255, 255, 255] };
+    draw_glyphs(&mut image, &[glyph], fg_col, 0, 0);
+
+    // Only the part of the glyph which is inside the image is drawn
+    assert!((0..30).any(|y| image.get_pixel(0, y).data[3] > 0));
+    assert!((0..30).all(|y| image.get_pixel(29, y).data[3] == 0));
+}
+
+#[test]
+fn parse_markup_splits_runs() {
+    let runs = parse_markup("<span fg=\"#ff0000\">a</span> <b>b<i>c</i></b>%{F#00ff00}d%{F-}&lt;");
+    let red = Some(image::Rgba { data: [255, 0, 0, 255] });
+    let green = Some(image::Rgba { data: [0, 255, 0, 255] });
+
+    let texts: Vec<&str> = runs.iter().map(|run| run.text.as_str()).collect();
+    assert_eq!(texts, vec!["a", " ", "b", "c", "d", "<"]);
+    assert_eq!(runs[0].fg, red);
+    assert!(runs[2].bold && !runs[2].italic);
+    assert!(runs[3].bold && runs[3].italic);
+    assert_eq!(runs[4].fg, green);
+    assert_eq!(runs[5].fg, None);
+}
+
+#[test]
+fn parse_markup_keeps_unknown_tags() {
+    let runs = parse_markup("a <tag> b");
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0].text, "a <tag> b");
+}
@@ -1,13 +1,224 @@
+use toml;
+use std::cmp;
+use std::thread;
+use std::process;
+use std::collections;
+
+use parse_input;
+
+// Linux input event codes, used as button numbers by Wayland
+pub const BTN_LEFT: u32 = 0x110;
+pub const BTN_RIGHT: u32 = 0x111;
+pub const BTN_MIDDLE: u32 = 0x112;
+
 #[derive(Copy, Clone)]
 pub enum ButtonState {
     PRESSED,
     RELEASED,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScrollDirection {
+    UP,
+    DOWN,
+    LEFT,
+    RIGHT,
+}
+
 #[derive(Clone)]
 pub struct MouseEvent {
     pub state: Option<ButtonState>,
     pub button: Option<u32>,
+    // Every event carries at most a single scroll step
+    pub scroll: Option<ScrollDirection>,
     pub x: f64,
     pub y: f64,
+    // Absolute position on the screen in logical pixels, like i3bar reports it
+    pub root_x: f64,
+    pub root_y: f64,
+    // Buffer pixels per logical pixel of `x` and `y`, set by the bar creator
+    pub scale: u32,
+}
+
+// Commands spawned by mouse buttons, keyed by X11 style button number
+#[derive(Clone)]
+pub struct MouseCommands {
+    commands: collections::HashMap<u32, String>,
+    // Used by all buttons without a command of their own, scrolling excluded
+    fallback: Option<String>,
+    // Redraw the block once a command has finished
+    pub refresh: bool,
+    // Display the output of a command instead of the block's text, implies `refresh`
+    pub replace_text: bool,
+}
+
+impl MouseCommands {
+    // Reads the commands from the `[block.mouse]` table
+    pub fn from_toml(mouse_val: Option<&toml::Value>) -> MouseCommands {
+        let mut commands = collections::HashMap::new();
+        let mut fallback = None;
+        let mut refresh = false;
+        let mut replace_text = false;
+
+        if let Some(mouse_val) = mouse_val {
+            if let Some(table) = mouse_val.as_table() {
+                for (key, value) in table {
+                    if !key.starts_with("button_") {
+                        continue;
+                    }
+
+                    if let (Ok(button), Some(command)) = (key[7..].parse::<u32>(), value.as_str()) {
+                        commands.insert(button, command.to_owned());
+                    }
+                }
+            }
+
+            let named = [("left_command", 1),
+                         ("middle_command", 2),
+                         ("right_command", 3),
+                         ("scroll_up_command", 4),
+                         ("scroll_down_command", 5)];
+            for &(name, button) in &named {
+                if let Ok(command) = parse_input::toml_value_to_string(mouse_val, name) {
+                    commands.insert(button, command);
+                }
+            }
+
+            fallback = parse_input::toml_value_to_string(mouse_val, "command").ok();
+            refresh = parse_input::toml_value_to_bool(mouse_val, "refresh").unwrap_or(false);
+            replace_text = parse_input::toml_value_to_bool(mouse_val, "replace_text")
+                .unwrap_or(false);
+        }
+
+        MouseCommands {
+            commands: commands,
+            fallback: fallback,
+            refresh: refresh,
+            replace_text: replace_text,
+        }
+    }
+
+    // Runs the command of the button in the background, the caller has to wait for it
+    // Stdout of the returned process is piped when `replace_text` is set
+    pub fn run(&self,
+               mouse_event: &MouseEvent,
+               name: &str,
+               width: u32,
+               height: u32)
+               -> Option<process::Child> {
+        self.start(mouse_event, name, width, height, self.replace_text)
+    }
+
+    // Runs the command of the button in the background without reading its output
+    pub fn spawn(&self, mouse_event: &MouseEvent, name: &str, width: u32, height: u32) {
+        if let Some(mut child) = self.start(mouse_event, name, width, height, false) {
+            thread::spawn(move || {
+                let _ = child.wait();
+            });
+        }
+    }
+
+    // The environment matches the one i3blocks uses for its click events, with the variables
+    // of both i3blocks 1.4 and 1.5
+    // Sizes are in logical pixels
+    fn start(&self,
+             mouse_event: &MouseEvent,
+             name: &str,
+             width: u32,
+             height: u32,
+             pipe_stdout: bool)
+             -> Option<process::Child> {
+        let button = match (mouse_event.scroll, mouse_event.state, mouse_event.button) {
+            (Some(direction), _, _) => scroll_number(direction),
+            (None, Some(ButtonState::RELEASED), Some(button)) => button_number(button),
+            _ => return None,
+        };
+
+        let command = match self.commands.get(&button) {
+            Some(command) => command,
+            None if mouse_event.scroll.is_none() => {
+                match self.fallback {
+                    Some(ref command) => command,
+                    None => return None,
+                }
+            }
+            None => return None,
+        };
+
+        let stdout = if pipe_stdout {
+            process::Stdio::piped()
+        } else {
+            process::Stdio::inherit()
+        };
+
+        let scale = cmp::max(mouse_event.scale, 1);
+        let (root_x, root_y) = (mouse_event.root_x as i32, mouse_event.root_y as i32);
+        let relative_x = (mouse_event.x / scale as f64) as i32;
+        let relative_y = (mouse_event.y / scale as f64) as i32;
+        let (width, height) = (width / scale, height / scale);
+
+        process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("BLOCK_NAME", name)
+            .env("BLOCK_BUTTON", button.to_string())
+            .env("BLOCK_X", root_x.to_string())
+            .env("BLOCK_Y", root_y.to_string())
+            .env("BLOCK_WIDTH", width.to_string())
+            .env("name", name)
+            .env("button", button.to_string())
+            .env("x", root_x.to_string())
+            .env("y", root_y.to_string())
+            .env("relative_x", relative_x.to_string())
+            .env("relative_y", relative_y.to_string())
+            .env("width", width.to_string())
+            .env("height", height.to_string())
+            .stdout(stdout)
+            .spawn()
+            .ok()
+    }
+}
+
+// Converts Linux input event codes to X11 button numbers
+// Buttons after the middle button continue at 8, after the X11 scroll buttons
+pub fn button_number(button: u32) -> u32 {
+    match button {
+        BTN_LEFT => 1,
+        BTN_MIDDLE => 2,
+        BTN_RIGHT => 3,
+        _ => button.saturating_sub(BTN_LEFT) + 5,
+    }
+}
+
+// X11 reports scrolling as buttons 4 to 7
+pub fn scroll_number(direction: ScrollDirection) -> u32 {
+    match direction {
+        ScrollDirection::UP => 4,
+        ScrollDirection::DOWN => 5,
+        ScrollDirection::LEFT => 6,
+        ScrollDirection::RIGHT => 7,
+    }
+}
+
+#[test]
+fn button_number_matches_x11_buttons() {
+    assert_eq!(button_number(BTN_LEFT), 1);
+    assert_eq!(button_number(BTN_MIDDLE), 2);
+    assert_eq!(button_number(BTN_RIGHT), 3);
+    assert_eq!(button_number(0x113), 8);
+    assert_eq!(button_number(0x114), 9);
+}
+
+#[test]
+fn mouse_commands_prefer_named_buttons() {
+    let mouse_val: toml::Value = "command = \"all\"\nleft_command = \"left\"\nbutton_1 = \"one\"\n\
+                                  button_9 = \"nine\"\nscroll_up_command = \"up\"\n"
+        .parse()
+        .unwrap();
+    let commands = MouseCommands::from_toml(Some(&mouse_val));
+
+    assert_eq!(commands.commands.get(&1).unwrap(), "left");
+    assert_eq!(commands.commands.get(&9).unwrap(), "nine");
+    assert_eq!(commands.commands.get(&4).unwrap(), "up");
+    assert_eq!(commands.fallback.as_ref().unwrap(), "all");
 }
@@ -0,0 +1,22 @@
+// Sent by the panels whenever an output has been added, changed or removed
+#[derive(Clone)]
+pub struct OutputEvent {
+    pub id: u32,
+    // Logical width, a width of 0 means that the output has been removed
+    pub width: u32,
+    // Number of buffer pixels per logical pixel
+    pub scale: u32,
+    // Used for matching `[output."name"]` tables in the config, most specific first
+    pub names: Vec<String>,
+}
+
+// Sent to the panels whenever a bar has been rendered
+pub struct BarImage {
+    pub output: u32,
+    // Pixels in ARGB8888 with the size of the whole bar
+    pub bytes: Vec<u8>,
+    pub height: i32,
+    pub scale: i32,
+    // Horizontal ranges `(x, width)` in buffer pixels which changed since the last image
+    pub damage: Vec<(i32, i32)>,
+}
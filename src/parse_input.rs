@@ -1,30 +1,37 @@
 use toml;
 use std::fs;
-use rusttype;
 use std::num;
 use std::path;
 use std::error;
 use std::io::Read;
-use std::{io, env};
+use std::env;
 use std::boxed::Box;
 use image::{self, GenericImage};
 
-use modules::{MODULES, Block};
+use font;
+use modules::{self, MODULES, Block};
 
 pub struct Config {
     // Defaults for each element:
     pub bg: image::DynamicImage,
     pub fg: image::Rgba<u8>,
-    pub font: Option<rusttype::Font<'static>>,
+    pub font: Option<Vec<font::Font>>,
     pub font_height: Option<u32>,
+    pub bold_font: Option<font::Font>,
+    pub italic_font: Option<font::Font>,
+    pub markup: bool,
     pub resize: bool, // TODO: Currently never used by anything
     pub width: u32,
     pub spacing: u32,
     pub interval: u32,
+    pub scale: u32,
+
+    // Exclusive to blocks:
+    pub name: String,
 
     // Exclusive to bar:
     pub bar_height: u32,
-    pub top: bool, // TODO: Currently not implemented in Wayland
+    pub top: bool,
     pub left_blocks: Vec<Box<Block>>,
     pub center_blocks: Vec<Box<Block>>,
     pub right_blocks: Vec<Box<Block>>,
@@ -39,10 +46,16 @@ impl Clone for Config {
             fg: self.fg,
             font: self.font.clone(),
             font_height: self.font_height,
+            bold_font: self.bold_font.clone(),
+            italic_font: self.italic_font.clone(),
+            markup: self.markup,
             resize: self.resize,
             width: self.width,
             spacing: self.spacing,
             interval: self.interval,
+            scale: self.scale,
+
+            name: self.name.clone(),
 
             bar_height: self.bar_height,
             top: self.top,
@@ -55,7 +68,7 @@ impl Clone for Config {
 
 // TODO: FAIL MORE OFTEN!
 // It should not be possible to specify an image as foreground without error
-pub fn read_config() -> Result<Config, Box<error::Error>> {
+pub fn read_config_value() -> Result<toml::Value, Box<error::Error>> {
     let mut config_buf = String::new();
     let mut config_file = fs::File::open(format!("{}/.config/leechbar/config.toml",
                                                  get_home_dir()?))?;
@@ -63,39 +76,109 @@ pub fn read_config() -> Result<Config, Box<error::Error>> {
     config_buf = config_buf.replace("\\", "\\\\"); // Escape escape characters in config file
     let config_val: toml::Value = config_buf.parse().map_err(|_| "Unable to parse config.")?;
 
-    Ok(parse_settings(&config_val)?)
+    Ok(config_val)
 }
 
-fn parse_settings(config_val: &toml::Value) -> Result<Config, Box<error::Error>> {
+// Blocks can be overridden for an output with a `[output."name"]` table
+// The first name in `output_names` with a matching table is used
+// Sizes in the config are logical pixels, the resulting config is multiplied by `scale`
+pub fn parse_settings(config_val: &toml::Value,
+                      output_names: &[String],
+                      scale: u32)
+                      -> Result<Config, Box<error::Error>> {
     let general = config_val.lookup("general").ok_or("Unable to find [general] in the config.")?;
+    let output = output_table(config_val, output_names);
+
+    let mut config = block_from_toml(general, &default_config(scale))?;
+    read_panel_settings(general, &mut config)?;
+
+    // Lines in the lemonbar format replace the blocks, read from stdin or `lemonbar_command`
+    let lemonbar_command = toml_value_to_string(general, "lemonbar_command").ok();
+    if lemonbar_command.is_some() || toml_value_to_bool(general, "lemonbar").unwrap_or(false) {
+        let mut blocks = modules::lemonbar::create_blocks(&config, lemonbar_command)?;
+        config.right_blocks = blocks.split_off(2);
+        config.center_blocks = blocks.split_off(1);
+        config.left_blocks = blocks;
+        return Ok(config);
+    }
+
+    config.left_blocks = toml_value_to_blocks(blocks_table(general, output, "left_blocks"),
+                                              config_val,
+                                              "left_blocks",
+                                              &config)?;
+    config.center_blocks = toml_value_to_blocks(blocks_table(general, output, "center_blocks"),
+                                                config_val,
+                                                "center_blocks",
+                                                &config)?;
+    config.right_blocks = toml_value_to_blocks(blocks_table(general, output, "right_blocks"),
+                                               config_val,
+                                               "right_blocks",
+                                               &config)?;
+
+    Ok(config)
+}
 
+// Reads only the fields needed to create the panel, without loading fonts or blocks
+pub fn parse_panel_settings(config_val: &toml::Value) -> Result<Config, Box<error::Error>> {
+    let general = config_val.lookup("general").ok_or("Unable to find [general] in the config.")?;
+    let mut config = default_config(1);
+    read_panel_settings(general, &mut config)?;
+    Ok(config)
+}
+
+fn default_config(scale: u32) -> Config {
     let mut black_img = image::DynamicImage::new_rgba8(1, 1);
     black_img.put_pixel(0, 0, image::Rgba::<u8> { data: [0, 0, 0, 255] });
-    let mut config = Config {
+    Config {
         fg: image::Rgba::<u8> { data: [255, 255, 255, 255] },
         bg: black_img,
         font: None,
         font_height: None,
+        bold_font: None,
+        italic_font: None,
+        markup: false,
         resize: false,
         width: 0,
         spacing: 0,
         interval: 0,
+        scale: scale,
+        name: String::new(),
         bar_height: 0,
         top: true,
         left_blocks: Vec::new(),
         center_blocks: Vec::new(),
         right_blocks: Vec::new(),
-    };
-    config = block_from_toml(general, &config)?;
+    }
+}
 
-    config.bar_height = toml_value_to_integer(general, "bar_height")? as u32;
-    config.top = toml_value_to_bool(general, "top").unwrap_or(true);
+fn read_panel_settings(general_val: &toml::Value,
+                       config: &mut Config)
+                       -> Result<(), Box<error::Error>> {
+    config.bar_height = toml_value_to_integer(general_val, "bar_height")? * config.scale;
+    config.top = toml_value_to_bool(general_val, "top").unwrap_or(true);
+    Ok(())
+}
 
-    config.left_blocks = toml_value_to_blocks(general, config_val, "left_blocks", &config)?;
-    config.center_blocks = toml_value_to_blocks(general, config_val, "center_blocks", &config)?;
-    config.right_blocks = toml_value_to_blocks(general, config_val, "right_blocks", &config)?;
+fn output_table<'a>(config_val: &'a toml::Value,
+                    output_names: &[String])
+                    -> Option<&'a toml::Value> {
+    let outputs = match config_val.lookup("output").and_then(|o| o.as_table()) {
+        Some(outputs) => outputs,
+        None => return None,
+    };
 
-    Ok(config)
+    output_names.iter().filter_map(|name| outputs.get(name)).next()
+}
+
+// Returns the table which should be used for a list of blocks
+fn blocks_table<'a>(general_val: &'a toml::Value,
+                    output_val: Option<&'a toml::Value>,
+                    name: &str)
+                    -> &'a toml::Value {
+    match output_val {
+        Some(output_val) if output_val.lookup(name).is_some() => output_val,
+        _ => general_val,
+    }
 }
 
 // Creates a Block from a toml field
@@ -109,19 +192,33 @@ fn block_from_toml(general_val: &toml::Value,
     config.bg = toml_value_to_image(general_val, "bg").unwrap_or_else(|_| fallback.bg.clone());
     config.fg = toml_value_to_rgba(general_val, "fg").unwrap_or(fallback.fg);
     config.resize = toml_value_to_bool(general_val, "resize").unwrap_or(fallback.resize);
-    config.width = toml_value_to_integer(general_val, "width").unwrap_or(fallback.width);
-    config.spacing = toml_value_to_integer(general_val, "spacing").unwrap_or(fallback.spacing);
+    config.markup = toml_value_to_bool(general_val, "markup").unwrap_or(fallback.markup);
+    config.width = toml_value_to_integer(general_val, "width")
+        .map(|width| width * fallback.scale)
+        .unwrap_or(fallback.width);
+    config.spacing = toml_value_to_integer(general_val, "spacing")
+        .map(|spacing| spacing * fallback.scale)
+        .unwrap_or(fallback.spacing);
     config.interval = toml_value_to_integer(general_val, "interval").unwrap_or(fallback.interval);
 
     // Unwrap because if these missing it's over anyways.
-    config.font = Some(toml_value_to_font(general_val, "font").unwrap_or_else(|_| {
+    config.font = Some(toml_value_to_fonts(general_val, "font").unwrap_or_else(|_| {
         fallback.font.clone().ok_or("rusttype::Font required in [general].").unwrap()
     }));
     config.font_height = Some(toml_value_to_integer(general_val, "font_height")
+        .map(|font_height| font_height * fallback.scale)
         .unwrap_or_else(|_| {
             fallback.font_height.ok_or("rusttype::Font Height required in [general].").unwrap()
         }));
 
+    // Used for bold and italic markup, the regular font is used if these are missing
+    config.bold_font = toml_value_to_font(general_val, "bold_font")
+        .ok()
+        .or_else(|| fallback.bold_font.clone());
+    config.italic_font = toml_value_to_font(general_val, "italic_font")
+        .ok()
+        .or_else(|| fallback.italic_font.clone());
+
     Ok(config)
 }
 
@@ -142,7 +239,8 @@ fn toml_value_to_blocks(general_val: &toml::Value,
 
         let block_val = config_val.lookup(block_name)
             .ok_or_else(|| format!("Could not find toml value {}.", block_name))?;
-        let block_config = block_from_toml(block_val, config)?;
+        let mut block_config = block_from_toml(block_val, config)?;
+        block_config.name = block_name.to_owned();
 
         let module_name = toml_value_to_string(block_val, "module")?;
         blocks.push(MODULES.get(module_name.as_str())
@@ -197,23 +295,50 @@ pub fn toml_value_to_image(general_val: &toml::Value,
     }
 }
 
-// Uses string as path to load a font file
+// Loads a font by path or fontconfig name
 pub fn toml_value_to_font(general_val: &toml::Value,
                           name: &str)
-                          -> Result<rusttype::Font<'static>, Box<error::Error>> {
-    let home = get_home_dir()?;
-    let font_string =
-        toml_value_to_string(general_val, name)?.replace("$", &home).replace("~", &home);
+                          -> Result<font::Font, Box<error::Error>> {
+    font::load_font(&toml_value_to_string(general_val, name)?)
+}
+
+// Reads either a single font or a list of fonts used as fallback for missing glyphs
+pub fn toml_value_to_fonts(general_val: &toml::Value,
+                           name: &str)
+                           -> Result<Vec<font::Font>, Box<error::Error>> {
+    let fonts_val = general_val.lookup(name)
+        .ok_or_else(|| format!("Could not find toml value {}.", name))?;
+    let fonts = match *fonts_val {
+        toml::Value::Array(ref font_vals) => {
+            let mut fonts = Vec::new();
+            for font_val in font_vals {
+                let font_string = font_val.as_str().ok_or("Toml value not a string.")?;
+                fonts.push(font::load_font(font_string)?);
+            }
+            fonts
+        }
+        _ => vec![toml_value_to_font(general_val, name)?],
+    };
 
-    let font_file = fs::File::open(&font_string)?;
-    let font_data = font_file.bytes().collect::<Result<Vec<u8>, io::Error>>()?;
-    let collection = rusttype::FontCollection::from_bytes(font_data);
-    let font = collection.into_font().ok_or("Please only use valid TTF fonts.")?;
+    if fonts.is_empty() {
+        return Err(format!("{} needs at least one font.", name).into());
+    }
 
-    Ok(font)
+    Ok(fonts)
 }
 
-fn string_to_rgba(col_string: &str) -> Result<image::Rgba<u8>, num::ParseIntError> {
+// Parses colors in the `#RRGGBB` or `#RRGGBBAA` format from external sources
+// Unlike `string_to_rgba` this never panics on invalid input
+pub fn parse_color(color: &str) -> Option<image::Rgba<u8>> {
+    if (color.len() != 7 && color.len() != 9) || !color.starts_with('#') ||
+       !color[1..].chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+
+    string_to_rgba(color).ok()
+}
+
+pub fn string_to_rgba(col_string: &str) -> Result<image::Rgba<u8>, num::ParseIntError> {
     let red_string = col_string[1..3].to_lowercase();
     let blue_string = col_string[5..7].to_lowercase();
     let green_string = col_string[3..5].to_lowercase();
@@ -233,8 +358,37 @@ fn string_to_rgba(col_string: &str) -> Result<image::Rgba<u8>, num::ParseIntErro
     Ok(image::Rgba { data: [red, green, blue, alpha] })
 }
 
-fn get_home_dir() -> Result<String, String> {
+pub fn get_home_dir() -> Result<String, String> {
     let home_dir = env::home_dir().ok_or("Could not find home dir.")?;
     let home_str = home_dir.to_string_lossy();
     Ok(home_str.to_string())
 }
+
+#[test]
+fn output_table_prefers_first_matching_name() {
+    let config_val: toml::Value = "[output.DP-1]\nleft_blocks = \"a\"\n\
+                                   [output.\"Dell U2415\"]\nleft_blocks = \"b\"\n"
+        .parse()
+        .unwrap();
+
+    let names = vec!["HDMI-1".to_owned(), "Dell U2415".to_owned(), "DP-1".to_owned()];
+    let output = output_table(&config_val, &names).unwrap();
+    assert_eq!(toml_value_to_string(output, "left_blocks").unwrap(), "b");
+
+    assert!(output_table(&config_val, &["eDP-1".to_owned()]).is_none());
+}
+
+#[test]
+fn blocks_table_falls_back_to_general() {
+    let config_val: toml::Value = "[general]\nleft_blocks = \"a\"\nright_blocks = \"b\"\n\
+                                   [output.DP-1]\nleft_blocks = \"c\"\n"
+        .parse()
+        .unwrap();
+    let general = config_val.lookup("general").unwrap();
+    let output = output_table(&config_val, &["DP-1".to_owned()]);
+
+    let left = blocks_table(general, output, "left_blocks");
+    let right = blocks_table(general, output, "right_blocks");
+    assert_eq!(toml_value_to_string(left, "left_blocks").unwrap(), "c");
+    assert_eq!(toml_value_to_string(right, "right_blocks").unwrap(), "b");
+}
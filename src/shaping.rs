@@ -0,0 +1,238 @@
+use libc;
+use std::ptr;
+use std::slice;
+use rusttype;
+use std::sync::Arc;
+use unicode_bidi;
+
+use font;
+
+const HB_MEMORY_MODE_READONLY: libc::c_int = 1;
+const HB_DIRECTION_LTR: libc::c_int = 4;
+const HB_DIRECTION_RTL: libc::c_int = 5;
+// `hhea` as a HarfBuzz tag
+const HHEA_TAG: u32 = 0x68686561;
+
+enum HbBlob {}
+enum HbFace {}
+enum HbFont {}
+enum HbBuffer {}
+
+#[repr(C)]
+struct HbGlyphInfo {
+    codepoint: u32,
+    mask: u32,
+    cluster: u32,
+    var1: u32,
+    var2: u32,
+}
+
+#[repr(C)]
+struct HbGlyphPosition {
+    x_advance: i32,
+    y_advance: i32,
+    x_offset: i32,
+    y_offset: i32,
+    var: u32,
+}
+
+#[link(name = "harfbuzz")]
+extern "C" {
+    fn hb_blob_create(data: *const libc::c_char,
+                      length: libc::c_uint,
+                      mode: libc::c_int,
+                      user_data: *mut libc::c_void,
+                      destroy: Option<extern "C" fn(*mut libc::c_void)>)
+                      -> *mut HbBlob;
+    fn hb_blob_destroy(blob: *mut HbBlob);
+    fn hb_blob_get_data(blob: *mut HbBlob, length: *mut libc::c_uint) -> *const libc::c_char;
+    fn hb_face_create(blob: *mut HbBlob, index: libc::c_uint) -> *mut HbFace;
+    fn hb_face_destroy(face: *mut HbFace);
+    fn hb_face_get_upem(face: *mut HbFace) -> libc::c_uint;
+    fn hb_face_reference_table(face: *mut HbFace, tag: u32) -> *mut HbBlob;
+    fn hb_font_create(face: *mut HbFace) -> *mut HbFont;
+    fn hb_font_destroy(font: *mut HbFont);
+    fn hb_ot_font_set_funcs(font: *mut HbFont);
+    fn hb_font_set_scale(font: *mut HbFont, x_scale: libc::c_int, y_scale: libc::c_int);
+    fn hb_font_make_immutable(font: *mut HbFont);
+    fn hb_buffer_create() -> *mut HbBuffer;
+    fn hb_buffer_destroy(buffer: *mut HbBuffer);
+    fn hb_buffer_add_utf8(buffer: *mut HbBuffer,
+                          text: *const libc::c_char,
+                          text_length: libc::c_int,
+                          item_offset: libc::c_uint,
+                          item_length: libc::c_int);
+    fn hb_buffer_set_direction(buffer: *mut HbBuffer, direction: libc::c_int);
+    fn hb_buffer_guess_segment_properties(buffer: *mut HbBuffer);
+    fn hb_buffer_get_glyph_infos(buffer: *mut HbBuffer,
+                                 length: *mut libc::c_uint)
+                                 -> *mut HbGlyphInfo;
+    fn hb_buffer_get_glyph_positions(buffer: *mut HbBuffer,
+                                     length: *mut libc::c_uint)
+                                     -> *mut HbGlyphPosition;
+    fn hb_shape(font: *mut HbFont,
+                buffer: *mut HbBuffer,
+                features: *const libc::c_void,
+                num_features: libc::c_uint);
+}
+
+// HarfBuzz font of a font face, created once when the font is loaded
+pub struct Shaper {
+    blob: *mut HbBlob,
+    face: *mut HbFace,
+    hb_font: *mut HbFont,
+    // Font units from ascent to descent in `hhea`, rusttype scales this height to the font height
+    units: libc::c_int,
+    // The blob points into the font data
+    _data: Arc<Box<[u8]>>,
+}
+
+// The HarfBuzz objects are immutable once created, shaping only reads them
+unsafe impl Send for Shaper {}
+unsafe impl Sync for Shaper {}
+
+impl Shaper {
+    pub fn new(data: Arc<Box<[u8]>>, index: usize) -> Shaper {
+        unsafe {
+            let blob = hb_blob_create(data.as_ptr() as *const libc::c_char,
+                                      data.len() as libc::c_uint,
+                                      HB_MEMORY_MODE_READONLY,
+                                      ptr::null_mut(),
+                                      None);
+            let face = hb_face_create(blob, index as libc::c_uint);
+            let hb_font = hb_font_create(face);
+            hb_ot_font_set_funcs(hb_font);
+
+            // Positions are in font units
+            let upem = hb_face_get_upem(face) as libc::c_int;
+            hb_font_set_scale(hb_font, upem, upem);
+            let units = match hhea_units(face) {
+                Some(units) if units > 0 => units,
+                _ => upem,
+            };
+            hb_font_make_immutable(hb_font);
+
+            Shaper {
+                blob: blob,
+                face: face,
+                hb_font: hb_font,
+                units: units,
+                _data: data,
+            }
+        }
+    }
+}
+
+impl Drop for Shaper {
+    fn drop(&mut self) {
+        unsafe {
+            hb_font_destroy(self.hb_font);
+            hb_face_destroy(self.face);
+            hb_blob_destroy(self.blob);
+        }
+    }
+}
+
+// Ascent minus descent from the `hhea` table, like rusttype uses for scaling
+// The extents of HarfBuzz can come from the OS/2 table instead, which would not match
+unsafe fn hhea_units(face: *mut HbFace) -> Option<libc::c_int> {
+    let blob = hb_face_reference_table(face, HHEA_TAG);
+    let mut length = 0;
+    let data = hb_blob_get_data(blob, &mut length);
+
+    let units = if data.is_null() || length < 8 {
+        None
+    } else {
+        let data = slice::from_raw_parts(data as *const u8, length as usize);
+        let ascent = ((data[4] as u16) << 8 | data[5] as u16) as i16;
+        let descent = ((data[6] as u16) << 8 | data[7] as u16) as i16;
+        Some(ascent as libc::c_int - descent as libc::c_int)
+    };
+
+    hb_blob_destroy(blob);
+    units
+}
+
+// Splits a line into runs with a single direction, in the order they are displayed
+// Returns true for right-to-left runs
+pub fn bidi_runs(text: &str) -> Vec<(bool, &str)> {
+    let bidi_info = unicode_bidi::BidiInfo::new(text, None);
+
+    let mut runs = Vec::new();
+    for paragraph in &bidi_info.paragraphs {
+        let (levels, visual_runs) = bidi_info.visual_runs(paragraph, paragraph.range.clone());
+        for run in visual_runs {
+            runs.push((levels[run.start].is_rtl(), &text[run]));
+        }
+    }
+    runs
+}
+
+// Shapes text with a single font and direction, starting at `x` with the baseline at `baseline`
+// Returns the glyphs from left to right and the position after the last glyph
+pub fn shape<'a>(font: &'a font::Font,
+                 font_height: u32,
+                 text: &str,
+                 rtl: bool,
+                 x: f32,
+                 baseline: f32)
+                 -> (Vec<rusttype::PositionedGlyph<'a>>, f32) {
+    let scale = rusttype::Scale {
+        x: font_height as f32,
+        y: font_height as f32,
+    };
+
+    let shaper = &font.shaper;
+    let px_per_unit = font_height as f32 / shaper.units as f32;
+
+    let mut glyphs = Vec::new();
+    let mut caret = x;
+    unsafe {
+        let buffer = hb_buffer_create();
+        hb_buffer_add_utf8(buffer,
+                           text.as_ptr() as *const libc::c_char,
+                           text.len() as libc::c_int,
+                           0,
+                           text.len() as libc::c_int);
+        hb_buffer_set_direction(buffer, if rtl { HB_DIRECTION_RTL } else { HB_DIRECTION_LTR });
+        hb_buffer_guess_segment_properties(buffer);
+        hb_shape(shaper.hb_font, buffer, ptr::null(), 0);
+
+        let mut length = 0;
+        let infos = hb_buffer_get_glyph_infos(buffer, &mut length);
+        let positions = hb_buffer_get_glyph_positions(buffer, &mut length);
+        for i in 0..length as isize {
+            let info = &*infos.offset(i);
+            let position = &*positions.offset(i);
+
+            let glyph = font.rusttype_font.glyph(rusttype::GlyphId(info.codepoint));
+            if let Some(glyph) = glyph {
+                let point = rusttype::point(caret + position.x_offset as f32 * px_per_unit,
+                                            baseline - position.y_offset as f32 * px_per_unit);
+                glyphs.push(glyph.scaled(scale).positioned(point));
+            }
+            caret += position.x_advance as f32 * px_per_unit;
+        }
+
+        hb_buffer_destroy(buffer);
+    }
+
+    (glyphs, caret)
+}
+
+#[test]
+fn bidi_runs_reorders_rtl_text() {
+    let runs = bidi_runs("ab \u{5d0}\u{5d1} cd");
+    assert_eq!(runs,
+               vec![(false, "ab "), (true, "\u{5d0}\u{5d1}"), (false, " cd")]);
+}
+
+#[test]
+fn shaper_units_match_rusttype_scale() {
+    let font = font::load_font("/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf").unwrap();
+    let units = font.shaper.units as f32;
+
+    // rusttype scales the height from ascent to descent to the pixel height
+    let v_metrics = font.rusttype_font.v_metrics(rusttype::Scale::uniform(units));
+    assert!((v_metrics.ascent - v_metrics.descent - units).abs() < 0.01);
+}
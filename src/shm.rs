@@ -0,0 +1,225 @@
+use libc;
+use std::fs;
+use std::io;
+use std::ptr;
+use std::error;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use wayland_client::{self, Proxy};
+use wayland_client::protocol::{wl_shm, wl_shm_pool, wl_buffer};
+
+// Number of buffers every pool starts with, more are added when all of them are busy
+const BUFFER_COUNT: usize = 2;
+
+// The user data of a buffer is set to this while the compositor is reading from it
+// Proxies share their user data, so this is visible to the handler and the pool
+const BUSY: *mut () = 1 as *mut ();
+
+// Marks buffers as free again once the compositor has released them
+pub struct BufferHandler;
+
+impl wl_buffer::Handler for BufferHandler {
+    fn release(&mut self,
+               _evqh: &mut wayland_client::EventQueueHandle,
+               proxy: &wl_buffer::WlBuffer) {
+        proxy.set_user_data(ptr::null_mut());
+    }
+}
+declare_handler!(BufferHandler, wl_buffer::Handler, wl_buffer::WlBuffer);
+
+// Memory shared with the compositor, split into multiple buffers with the size of a bar
+// A new frame is always drawn into a buffer which isn't used by the compositor anymore
+pub struct ShmPool {
+    file: fs::File,
+    memory: *mut u8,
+    size: usize,
+    pool: wl_shm_pool::WlShmPool,
+    // Buffers with their offset and size in the pool
+    buffers: Vec<(wl_buffer::WlBuffer, usize, usize)>,
+    // Buffers of an old size which are still used by the compositor
+    retired_buffers: Vec<(wl_buffer::WlBuffer, usize, usize)>,
+    width: i32,
+    height: i32,
+    handler_id: usize,
+}
+
+impl ShmPool {
+    // Buffers are registered with the `BufferHandler` at `handler_id`
+    pub fn new(shm: &wl_shm::WlShm, handler_id: usize) -> Result<ShmPool, Box<error::Error>> {
+        // Pools can't be empty, so start with a single pixel until the first draw
+        let size = 4;
+        let file = create_memfd()?;
+        file.set_len(size as u64)?;
+        let memory = map_file(&file, size)?;
+
+        let pool = match shm.create_pool(file.as_raw_fd(), size as i32) {
+            wayland_client::RequestResult::Sent(pool) => pool,
+            wayland_client::RequestResult::Destroyed => {
+                unsafe { libc::munmap(memory as *mut libc::c_void, size) };
+                Err("SHM already destroyed.".to_owned())?
+            }
+        };
+
+        Ok(ShmPool {
+            file: file,
+            memory: memory,
+            size: size,
+            pool: pool,
+            buffers: Vec::new(),
+            retired_buffers: Vec::new(),
+            width: 0,
+            height: 0,
+            handler_id: handler_id,
+        })
+    }
+
+    // Copies an ARGB8888 image into a free buffer, which is then marked as busy
+    // All buffers are recreated when the size of the image changes, the memory of busy buffers
+    // is only reused once the compositor has released them
+    pub fn draw(&mut self,
+                evqh: &mut wayland_client::EventQueueHandle,
+                bytes: &[u8],
+                width: i32,
+                height: i32)
+                -> Result<&wl_buffer::WlBuffer, Box<error::Error>> {
+        if bytes.len() != width as usize * height as usize * 4 {
+            return Err("Image does not match the size of the bar.".into());
+        }
+
+        if width != self.width || height != self.height {
+            self.retire_buffers();
+            self.width = width;
+            self.height = height;
+        }
+        self.destroy_released_buffers();
+
+        while self.buffers.len() < BUFFER_COUNT {
+            self.add_buffer(evqh)?;
+        }
+
+        let free_index =
+            self.buffers.iter().position(|&(ref buffer, _, _)| buffer.get_user_data().is_null());
+        let index = match free_index {
+            Some(index) => index,
+            None => {
+                self.add_buffer(evqh)?;
+                self.buffers.len() - 1
+            }
+        };
+
+        let (ref buffer, offset, _) = self.buffers[index];
+        unsafe {
+            let buffer_memory = self.memory.offset(offset as isize);
+            ptr::copy_nonoverlapping(bytes.as_ptr(), buffer_memory, bytes.len());
+        }
+
+        buffer.set_user_data(BUSY);
+        Ok(buffer)
+    }
+
+    fn add_buffer(&mut self,
+                  evqh: &mut wayland_client::EventQueueHandle)
+                  -> Result<(), Box<error::Error>> {
+        let buffer_size = self.width as usize * self.height as usize * 4;
+        let offset = self.buffers
+            .iter()
+            .chain(self.retired_buffers.iter())
+            .map(|&(_, offset, size)| offset + size)
+            .max()
+            .unwrap_or(0);
+        self.grow(offset + buffer_size)?;
+
+        let buffer = match self.pool.create_buffer(offset as i32,
+                                                   self.width,
+                                                   self.height,
+                                                   self.width * 4,
+                                                   wl_shm::Format::Argb8888) {
+            wayland_client::RequestResult::Sent(buffer) => buffer,
+            wayland_client::RequestResult::Destroyed => Err("Pool already destroyed.".to_owned())?,
+        };
+
+        evqh.register::<_, BufferHandler>(&buffer, self.handler_id);
+        self.buffers.push((buffer, offset, buffer_size));
+
+        Ok(())
+    }
+
+    // Pools can only grow, so the memory is kept when the bar gets smaller
+    fn grow(&mut self, size: usize) -> Result<(), Box<error::Error>> {
+        if size <= self.size {
+            return Ok(());
+        }
+
+        unsafe { libc::munmap(self.memory as *mut libc::c_void, self.size) };
+        self.memory = ptr::null_mut();
+        self.size = 0;
+
+        self.file.set_len(size as u64)?;
+        self.memory = map_file(&self.file, size)?;
+        self.size = size;
+        self.pool.resize(size as i32);
+
+        Ok(())
+    }
+
+    // Free buffers are destroyed, busy ones are kept until the compositor releases them
+    fn retire_buffers(&mut self) {
+        let buffers: Vec<_> = self.buffers.drain(..).collect();
+        self.retired_buffers.extend(buffers);
+        self.destroy_released_buffers();
+    }
+
+    fn destroy_released_buffers(&mut self) {
+        let (released, busy): (Vec<_>, Vec<_>) = self.retired_buffers
+            .drain(..)
+            .partition(|&(ref buffer, _, _)| buffer.get_user_data().is_null());
+        self.retired_buffers = busy;
+
+        for (buffer, _, _) in released {
+            buffer.destroy();
+        }
+    }
+
+    fn destroy_buffers(&mut self) {
+        for (buffer, _, _) in self.buffers.drain(..).chain(self.retired_buffers.drain(..)) {
+            buffer.destroy();
+        }
+    }
+}
+
+impl Drop for ShmPool {
+    fn drop(&mut self) {
+        self.destroy_buffers();
+        self.pool.destroy();
+
+        if !self.memory.is_null() {
+            unsafe { libc::munmap(self.memory as *mut libc::c_void, self.size) };
+        }
+    }
+}
+
+fn create_memfd() -> Result<fs::File, io::Error> {
+    let name = b"leechbar\0".as_ptr() as *const libc::c_char;
+    let fd = unsafe { libc::memfd_create(name, libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
+
+fn map_file(file: &fs::File, size: usize) -> Result<*mut u8, io::Error> {
+    let memory = unsafe {
+        libc::mmap(ptr::null_mut(),
+                   size,
+                   libc::PROT_READ | libc::PROT_WRITE,
+                   libc::MAP_SHARED,
+                   file.as_raw_fd(),
+                   0)
+    };
+
+    if memory == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(memory as *mut u8)
+}
@@ -1,73 +1,389 @@
+use libc;
 use std::fs;
+use std::io;
 use std::env;
+use std::cmp;
+use std::mem;
 use std::error;
 use std::thread;
-use std::sync::mpsc;
-use std::os::unix::io::AsRawFd;
-use wayland_client;
+use std::collections;
+use std::io::{Read, Write};
+use std::sync::{self, mpsc};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use wayland_client::{self, Proxy};
 use wayland_client::protocol::{wl_compositor, wl_shell, wl_shm, wl_shell_surface, wl_seat,
-                               wl_pointer, wl_surface, wl_output, wl_display, wl_registry};
+                               wl_pointer, wl_surface, wl_output, wl_display, wl_registry,
+                               wl_callback};
 
+use shm;
 use mouse;
-use self::generated::client::desktop_shell;
+use output::{OutputEvent, BarImage};
+use parse_input::Config;
+use self::generated::desktop_shell::client::desktop_shell;
+use self::generated::layer_shell::client::{zwlr_layer_shell_v1, zwlr_layer_surface_v1};
+use self::generated::xdg_output::client::{zxdg_output_manager_v1, zxdg_output_v1};
 
 mod generated {
     #![allow(dead_code,non_camel_case_types,unused_unsafe,unused_variables)]
     #![allow(non_upper_case_globals,non_snake_case,unused_imports)]
 
-    #[doc(hidden)]
-    pub mod interfaces {
+    pub mod desktop_shell {
         #[doc(hidden)]
-        pub use wayland_client::protocol_interfaces::{wl_output_interface, wl_surface_interface};
-        include!(concat!(env!("OUT_DIR"), "/desktop_shell_interfaces.rs"));
+        pub mod interfaces {
+            #[doc(hidden)]
+            pub use wayland_client::protocol_interfaces::{wl_output_interface,
+                                                          wl_surface_interface};
+            include!(concat!(env!("OUT_DIR"), "/desktop_shell_interfaces.rs"));
+        }
+
+        #[doc(hidden)]
+        pub mod client {
+            #[doc(hidden)]
+            pub use wayland_client::{Proxy, Handler, EventQueueHandle, RequestResult};
+            #[doc(hidden)]
+            pub use super::interfaces;
+            #[doc(hidden)]
+            pub use wayland_client::protocol::{wl_surface, wl_region, wl_output};
+            include!(concat!(env!("OUT_DIR"), "/desktop_shell.rs"));
+        }
     }
 
-    #[doc(hidden)]
-    pub mod client {
+    // Only required because layer shell popups reference `xdg_popup`
+    pub mod xdg_shell {
         #[doc(hidden)]
-        pub use wayland_client::{Proxy, Handler, EventQueueHandle, RequestResult};
+        pub mod interfaces {
+            #[doc(hidden)]
+            pub use wayland_client::protocol_interfaces::{wl_output_interface,
+                                                          wl_seat_interface,
+                                                          wl_surface_interface};
+            include!(concat!(env!("OUT_DIR"), "/xdg_shell_interfaces.rs"));
+        }
+
         #[doc(hidden)]
-        pub use super::interfaces;
+        pub mod client {
+            #[doc(hidden)]
+            pub use wayland_client::{Proxy, Handler, EventQueueHandle, RequestResult};
+            #[doc(hidden)]
+            pub use super::interfaces;
+            #[doc(hidden)]
+            pub use wayland_client::protocol::{wl_surface, wl_seat, wl_output};
+            include!(concat!(env!("OUT_DIR"), "/xdg_shell.rs"));
+        }
+    }
+
+    pub mod xdg_output {
         #[doc(hidden)]
-        pub use wayland_client::protocol::{wl_surface, wl_region, wl_output};
-        include!(concat!(env!("OUT_DIR"), "/desktop_shell.rs"));
+        pub mod interfaces {
+            #[doc(hidden)]
+            pub use wayland_client::protocol_interfaces::wl_output_interface;
+            include!(concat!(env!("OUT_DIR"), "/xdg_output_interfaces.rs"));
+        }
+
+        #[doc(hidden)]
+        pub mod client {
+            #[doc(hidden)]
+            pub use wayland_client::{Proxy, Handler, EventQueueHandle, RequestResult};
+            #[doc(hidden)]
+            pub use super::interfaces;
+            #[doc(hidden)]
+            pub use wayland_client::protocol::wl_output;
+            include!(concat!(env!("OUT_DIR"), "/xdg_output.rs"));
+        }
+    }
+
+    pub mod layer_shell {
+        #[doc(hidden)]
+        pub mod interfaces {
+            #[doc(hidden)]
+            pub use wayland_client::protocol_interfaces::{wl_output_interface,
+                                                          wl_surface_interface};
+            #[doc(hidden)]
+            pub use super::super::xdg_shell::interfaces::xdg_popup_interface;
+            include!(concat!(env!("OUT_DIR"), "/layer_shell_interfaces.rs"));
+        }
+
+        #[doc(hidden)]
+        pub mod client {
+            #[doc(hidden)]
+            pub use wayland_client::{Proxy, Handler, EventQueueHandle, RequestResult};
+            #[doc(hidden)]
+            pub use super::interfaces;
+            #[doc(hidden)]
+            pub use wayland_client::protocol::{wl_surface, wl_output};
+            #[doc(hidden)]
+            pub use super::super::xdg_shell::client::xdg_popup;
+            include!(concat!(env!("OUT_DIR"), "/layer_shell.rs"));
+        }
     }
 }
 
+// Smooth scrolling distance in surface pixels which makes up a single scroll step
+const SCROLL_STEP: f64 = 10.;
+
 wayland_env!(WaylandEnv,
-             desktop_shell: desktop_shell::DesktopShell,
              compositor: wl_compositor::WlCompositor,
-             output: wl_output::WlOutput,
-             shell: wl_shell::WlShell,
              seat: wl_seat::WlSeat,
              shm: wl_shm::WlShm);
 
+// Protocol used for turning a surface into a bar
+// The layer shell is preferred, desktop_shell is only used if it's not available
+enum PanelShell {
+    LayerShell(zwlr_layer_shell_v1::ZwlrLayerShellV1),
+    DesktopShell(desktop_shell::DesktopShell, wl_shell::WlShell),
+}
+
+// Surface of the bar on a single output, identified by the global name of the output
+struct Bar {
+    id: u32,
+    output: wl_output::WlOutput,
+    surface: wl_surface::WlSurface,
+    layer_surface: Option<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1>,
+    xdg_output: Option<zxdg_output_v1::ZxdgOutputV1>,
+    // Connector name like `DP-1`, only sent by version 2 of xdg-output
+    connector_name: Option<String>,
+    // Make and model of the output
+    names: Vec<String>,
+    // Size of the current output mode in physical pixels
+    mode_width: u32,
+    mode_height: u32,
+    transform: wl_output::Transform,
+    // Width of the output in logical pixels, if xdg-output is available
+    logical_width: u32,
+    // Position and height of the output in the global space, if xdg-output is available
+    logical_x: i32,
+    logical_y: i32,
+    logical_height: u32,
+    // Width of the layer surface in logical pixels
+    configured_width: u32,
+    scale: u32,
+    // Last width, scale and names sent to the bar creator
+    sent: (u32, u32),
+    sent_names: Vec<String>,
+    committed: bool,
+}
+
 struct EventHandler {
-    resize_out: mpsc::Sender<u32>,
-    mouse_out: mpsc::Sender<mouse::MouseEvent>,
+    handler_id: usize,
+    bars: sync::Arc<sync::Mutex<Vec<Bar>>>,
+    new_outputs: Vec<(u32, wl_output::WlOutput)>,
+    compositor: wl_compositor::WlCompositor,
+    panel_shell: PanelShell,
+    xdg_output_manager: Option<zxdg_output_manager_v1::ZxdgOutputManagerV1>,
+    bar_height: u32,
+    top: bool,
+    resize_out: mpsc::Sender<OutputEvent>,
+    mouse_out: mpsc::Sender<(u32, mouse::MouseEvent)>,
     cursor_theme: wayland_client::cursor::CursorTheme,
     cursor_surface: wl_surface::WlSurface,
+    pointer_output: Option<u32>,
+    // Position of the bar below the pointer in the global space
+    pointer_origin: (f64, f64),
     last_x: f64,
     last_y: f64,
+    // Smooth scrolling distance which hasn't been sent as a step yet, indexed by axis
+    scroll_distance: [f64; 2],
+    // Axes which already sent the steps of the current wheel click with `axis_discrete`
+    discrete_axes: [bool; 2],
 }
 
 impl EventHandler {
-    fn new(resize_out: mpsc::Sender<u32>,
-           mouse_out: mpsc::Sender<mouse::MouseEvent>,
-           cursor_theme: wayland_client::cursor::CursorTheme,
-           cursor_surface: wl_surface::WlSurface)
-           -> Result<EventHandler, Box<error::Error>> {
-        Ok(EventHandler {
-            resize_out: resize_out,
-            mouse_out: mouse_out,
-            cursor_theme: cursor_theme,
-            cursor_surface: cursor_surface,
-            last_x: 0f64,
-            last_y: 0f64,
-        })
+    fn add_bar(&mut self,
+               evqh: &mut wayland_client::EventQueueHandle,
+               id: u32,
+               output: wl_output::WlOutput)
+               -> Result<(), Box<error::Error>> {
+        let surface = request_result_to_result(self.compositor.create_surface(),
+                                               "Compositor already destroyed.")?;
+
+        let layer_surface = match self.panel_shell {
+            PanelShell::LayerShell(ref layer_shell) => {
+                let layer = zwlr_layer_shell_v1::Layer::Top;
+                let layer_surface =
+                    request_result_to_result(layer_shell.get_layer_surface(&surface,
+                                                                           Some(&output),
+                                                                           layer,
+                                                                           "leechbar".to_owned()),
+                                             "Layer shell already destroyed.")?;
+
+                // Span the whole edge, the width is sent by the compositor in `configure`
+                let edge = if self.top {
+                    zwlr_layer_surface_v1::Top
+                } else {
+                    zwlr_layer_surface_v1::Bottom
+                };
+                layer_surface.set_anchor(edge | zwlr_layer_surface_v1::Left |
+                                         zwlr_layer_surface_v1::Right);
+                layer_surface.set_size(0, self.bar_height);
+                layer_surface.set_exclusive_zone(self.bar_height as i32);
+
+                // Commit without a buffer to receive the first configure event
+                surface.commit();
+
+                evqh.register::<_, EventHandler>(&layer_surface, self.handler_id);
+                Some(layer_surface)
+            }
+            PanelShell::DesktopShell(ref desktop_shell, ref shell) => {
+                let shell_surface = request_result_to_result(shell.get_shell_surface(&surface),
+                                                             "Surface already destroyed.")?;
+                shell_surface.set_toplevel();
+
+                // Make DesktopShell surface a bar
+                desktop_shell.set_panel(&output, &surface);
+
+                evqh.register::<_, EventHandler>(&shell_surface, self.handler_id);
+                None
+            }
+        };
+
+        let xdg_output = match self.xdg_output_manager {
+            Some(ref manager) => {
+                let xdg_output = request_result_to_result(manager.get_xdg_output(&output),
+                                                          "Output manager already destroyed.")?;
+                evqh.register::<_, EventHandler>(&xdg_output, self.handler_id);
+                Some(xdg_output)
+            }
+            None => None,
+        };
+
+        evqh.register::<_, EventHandler>(&output, self.handler_id);
+
+        let mut bars = self.bars.lock().map_err(|e| e.to_string())?;
+        bars.push(Bar {
+            id: id,
+            output: output,
+            surface: surface,
+            layer_surface: layer_surface,
+            xdg_output: xdg_output,
+            connector_name: None,
+            names: Vec::new(),
+            mode_width: 0,
+            mode_height: 0,
+            transform: wl_output::Transform::Normal,
+            logical_width: 0,
+            logical_x: 0,
+            logical_y: 0,
+            logical_height: 0,
+            configured_width: 0,
+            scale: 1,
+            sent: (0, 0),
+            sent_names: Vec::new(),
+            committed: false,
+        });
+
+        Ok(())
+    }
+
+    // Sends the logical width of a bar together with the scale it should be rendered at
+    // Nothing is sent if neither of them nor the names changed since the last event
+    fn send_output_event(&self, bar: &mut Bar) {
+        let width = match bar.layer_surface {
+            Some(_) => bar.configured_width,
+            None => logical_width(bar),
+        };
+
+        // The connector name can arrive after the bar has been sent, which resends it
+        let mut names: Vec<String> = bar.connector_name.iter().cloned().collect();
+        names.extend(bar.names.iter().cloned());
+
+        // A width of 0 would remove the bar, so wait until the size is known
+        if width > 0 && ((width, bar.scale) != bar.sent || names != bar.sent_names) {
+            bar.sent = (width, bar.scale);
+            bar.sent_names = names.clone();
+            let _ = self.resize_out.send(OutputEvent {
+                id: bar.id,
+                width: width,
+                scale: bar.scale,
+                names: names,
+            });
+        }
+    }
+
+    fn send_scroll(&self, direction: mouse::ScrollDirection, steps: u32) {
+        if let Some(output) = self.pointer_output {
+            for _ in 0..steps {
+                let _ = self.mouse_out.send((output,
+                                             mouse::MouseEvent {
+                                                 button: None,
+                                                 state: None,
+                                                 scroll: Some(direction),
+                                                 x: self.last_x,
+                                                 y: self.last_y,
+                                                 root_x: self.pointer_origin.0 + self.last_x,
+                                                 root_y: self.pointer_origin.1 + self.last_y,
+                                                 scale: 1,
+                                             }));
+            }
+        }
+    }
+
+    fn remove_bar(&mut self, id: u32) -> Result<(), Box<error::Error>> {
+        let mut bars = self.bars.lock().map_err(|e| e.to_string())?;
+        if let Some(index) = bars.iter().position(|bar| bar.id == id) {
+            let bar = bars.remove(index);
+            if let Some(ref layer_surface) = bar.layer_surface {
+                layer_surface.destroy();
+            }
+            if let Some(ref xdg_output) = bar.xdg_output {
+                xdg_output.destroy();
+            }
+            bar.surface.destroy();
+            if bar.output.version() >= 3 {
+                bar.output.release();
+            }
+
+            if self.pointer_output == Some(id) {
+                self.pointer_output = None;
+            }
+
+            let _ = self.resize_out.send(OutputEvent {
+                id: id,
+                width: 0,
+                scale: 1,
+                names: Vec::new(),
+            });
+        }
+
+        Ok(())
     }
 }
 
+impl wayland_client::Init for EventHandler {
+    fn init(&mut self, evqh: &mut wayland_client::EventQueueHandle, index: usize) {
+        self.handler_id = index;
+
+        // Create bars for all outputs which existed before the handler was added
+        let new_outputs = mem::replace(&mut self.new_outputs, Vec::new());
+        for (id, output) in new_outputs {
+            let _ = self.add_bar(evqh, id, output);
+        }
+    }
+}
+
+impl wl_registry::Handler for EventHandler {
+    fn global(&mut self,
+              evqh: &mut wayland_client::EventQueueHandle,
+              registry: &wl_registry::WlRegistry,
+              name: u32,
+              interface: String,
+              version: u32) {
+        if interface == "wl_output" {
+            let version = cmp::min(version, wl_output::WlOutput::supported_version());
+            if let Ok(output) = request_result_to_result(registry.bind(version, name),
+                                                         "Registry already destroyed.") {
+                let _ = self.add_bar(evqh, name, output);
+            }
+        }
+    }
+
+    fn global_remove(&mut self,
+                     _evqh: &mut wayland_client::EventQueueHandle,
+                     _registry: &wl_registry::WlRegistry,
+                     name: u32) {
+        let _ = self.remove_bar(name);
+    }
+}
+declare_handler!(EventHandler, wl_registry::Handler, wl_registry::WlRegistry);
+
 impl wl_shell_surface::Handler for EventHandler {
     fn ping(&mut self,
             _: &mut wayland_client::EventQueueHandle,
@@ -80,6 +396,41 @@ declare_handler!(EventHandler,
                  wl_shell_surface::Handler,
                  wl_shell_surface::WlShellSurface);
 
+impl zwlr_layer_surface_v1::Handler for EventHandler {
+    fn configure(&mut self,
+                 _evqh: &mut wayland_client::EventQueueHandle,
+                 proxy: &zwlr_layer_surface_v1::ZwlrLayerSurfaceV1,
+                 serial: u32,
+                 width: u32,
+                 _height: u32) {
+        proxy.ack_configure(serial);
+
+        // The next commit happens once the bar has been rendered with the new width
+        if let Ok(mut bars) = self.bars.lock() {
+            if let Some(bar) = bars.iter_mut().find(|bar| is_layer_surface(bar, proxy)) {
+                bar.configured_width = width;
+                self.send_output_event(bar);
+            }
+        }
+    }
+
+    fn closed(&mut self,
+              _evqh: &mut wayland_client::EventQueueHandle,
+              proxy: &zwlr_layer_surface_v1::ZwlrLayerSurfaceV1) {
+        let id = match self.bars.lock() {
+            Ok(bars) => bars.iter().find(|bar| is_layer_surface(bar, proxy)).map(|bar| bar.id),
+            Err(_) => None,
+        };
+
+        if let Some(id) = id {
+            let _ = self.remove_bar(id);
+        }
+    }
+}
+declare_handler!(EventHandler,
+                 zwlr_layer_surface_v1::Handler,
+                 zwlr_layer_surface_v1::ZwlrLayerSurfaceV1);
+
 impl wl_pointer::Handler for EventHandler {
     fn motion(&mut self,
               _evqh: &mut wayland_client::EventQueueHandle,
@@ -90,12 +441,19 @@ impl wl_pointer::Handler for EventHandler {
         self.last_x = surface_x;
         self.last_y = surface_y;
 
-        let _ = self.mouse_out.send(mouse::MouseEvent {
-            button: None,
-            state: None,
-            x: surface_x,
-            y: surface_y,
-        });
+        if let Some(output) = self.pointer_output {
+            let _ = self.mouse_out.send((output,
+                                         mouse::MouseEvent {
+                                             button: None,
+                                             state: None,
+                                             scroll: None,
+                                             x: surface_x,
+                                             y: surface_y,
+                                             root_x: self.pointer_origin.0 + surface_x,
+                                             root_y: self.pointer_origin.1 + surface_y,
+                                             scale: 1,
+                                         }));
+        }
     }
 
     fn button(&mut self,
@@ -110,12 +468,65 @@ impl wl_pointer::Handler for EventHandler {
             wl_pointer::ButtonState::Released => mouse::ButtonState::RELEASED,
         };
 
-        let _ = self.mouse_out.send(mouse::MouseEvent {
-            button: Some(button),
-            state: Some(state),
-            x: self.last_x,
-            y: self.last_y,
-        });
+        if let Some(output) = self.pointer_output {
+            let _ = self.mouse_out.send((output,
+                                         mouse::MouseEvent {
+                                             button: Some(button),
+                                             state: Some(state),
+                                             scroll: None,
+                                             x: self.last_x,
+                                             y: self.last_y,
+                                             root_x: self.pointer_origin.0 + self.last_x,
+                                             root_y: self.pointer_origin.1 + self.last_y,
+                                             scale: 1,
+                                         }));
+        }
+    }
+
+    fn axis(&mut self,
+            _evqh: &mut wayland_client::EventQueueHandle,
+            _proxy: &wl_pointer::WlPointer,
+            _time: u32,
+            axis: wl_pointer::Axis,
+            value: f64) {
+        // Wheels with discrete steps have already been handled by `axis_discrete`
+        let index = axis_index(axis);
+        if self.discrete_axes[index] {
+            self.discrete_axes[index] = false;
+            return;
+        }
+
+        // Touchpads scroll in small increments, these are combined to full steps
+        self.scroll_distance[index] += value;
+        let steps = (self.scroll_distance[index].abs() / SCROLL_STEP) as u32;
+        if steps > 0 {
+            let direction = scroll_direction(axis, self.scroll_distance[index]);
+            self.scroll_distance[index] %= SCROLL_STEP;
+            self.send_scroll(direction, steps);
+        }
+    }
+
+    fn axis_stop(&mut self,
+                 _evqh: &mut wayland_client::EventQueueHandle,
+                 _proxy: &wl_pointer::WlPointer,
+                 _time: u32,
+                 axis: wl_pointer::Axis) {
+        self.scroll_distance[axis_index(axis)] = 0f64;
+    }
+
+    fn axis_discrete(&mut self,
+                     _evqh: &mut wayland_client::EventQueueHandle,
+                     _proxy: &wl_pointer::WlPointer,
+                     axis: wl_pointer::Axis,
+                     discrete: i32) {
+        // Always followed by an `axis` event for the same axis, which is skipped
+        let index = axis_index(axis);
+        self.discrete_axes[index] = true;
+        self.scroll_distance[index] = 0f64;
+
+        if discrete != 0 {
+            self.send_scroll(scroll_direction(axis, discrete as f64), discrete.abs() as u32);
+        }
     }
 
     fn leave(&mut self,
@@ -123,21 +534,44 @@ impl wl_pointer::Handler for EventHandler {
              _proxy: &wl_pointer::WlPointer,
              _serial: u32,
              _surface: &wl_surface::WlSurface) {
-        let _ = self.mouse_out.send(mouse::MouseEvent {
-            button: None,
-            state: None,
-            x: -1f64,
-            y: -1f64,
-        });
+        self.scroll_distance = [0f64; 2];
+        self.discrete_axes = [false; 2];
+
+        if let Some(output) = self.pointer_output.take() {
+            let _ = self.mouse_out.send((output,
+                                         mouse::MouseEvent {
+                                             button: None,
+                                             state: None,
+                                             scroll: None,
+                                             x: -1f64,
+                                             y: -1f64,
+                                             root_x: -1f64,
+                                             root_y: -1f64,
+                                             scale: 1,
+                                         }));
+        }
     }
 
     fn enter(&mut self,
              _evqh: &mut wayland_client::EventQueueHandle,
              proxy: &wl_pointer::WlPointer,
              serial: u32,
-             _surface: &wl_surface::WlSurface,
+             surface: &wl_surface::WlSurface,
              _surface_x: f64,
              _surface_y: f64) {
+        // Route all following pointer events to the bar below the pointer
+        let (bar_height, top) = (self.bar_height, self.top);
+        let pointer_bar = match self.bars.lock() {
+            Ok(bars) => {
+                bars.iter()
+                    .find(|bar| bar.surface.equals(surface))
+                    .map(|bar| (bar.id, bar_origin(bar, bar_height, top)))
+            }
+            Err(_) => None,
+        };
+        self.pointer_output = pointer_bar.map(|(id, _)| id);
+        self.pointer_origin = pointer_bar.map_or((0f64, 0f64), |(_, origin)| origin);
+
         let cursor = self.cursor_theme.get_cursor("left_ptr").unwrap();
         let cursor_buffer = cursor.frame_buffer(0).unwrap();
         self.cursor_surface.attach(Some(&cursor_buffer), 0, 0);
@@ -149,25 +583,156 @@ impl wl_pointer::Handler for EventHandler {
 declare_handler!(EventHandler, wl_pointer::Handler, wl_pointer::WlPointer);
 
 impl wl_output::Handler for EventHandler {
+    fn geometry(&mut self,
+                _evqh: &mut wayland_client::EventQueueHandle,
+                proxy: &wl_output::WlOutput,
+                _x: i32,
+                _y: i32,
+                _physical_width: i32,
+                _physical_height: i32,
+                _subpixel: wl_output::Subpixel,
+                make: String,
+                model: String,
+                transform: wl_output::Transform) {
+        if let Ok(mut bars) = self.bars.lock() {
+            if let Some(bar) = bars.iter_mut().find(|bar| bar.output.equals(proxy)) {
+                bar.names = vec![format!("{} {}", make, model), model, make];
+                bar.transform = transform;
+
+                // Outputs before version 2 never send `done`
+                if proxy.version() < 2 {
+                    self.send_output_event(bar);
+                }
+            }
+        }
+    }
+
     fn mode(&mut self,
             _evqh: &mut wayland_client::EventQueueHandle,
-            _proxy: &wl_output::WlOutput,
-            _flags: wl_output::Mode,
+            proxy: &wl_output::WlOutput,
+            flags: wl_output::Mode,
             width: i32,
-            _height: i32,
+            height: i32,
             _refresh: i32) {
-        let _ = self.resize_out.send(width as u32);
+        // All supported modes are advertised, but only the current one is relevant
+        if !flags.contains(wl_output::Current) {
+            return;
+        }
+
+        if let Ok(mut bars) = self.bars.lock() {
+            if let Some(bar) = bars.iter_mut().find(|bar| bar.output.equals(proxy)) {
+                bar.mode_width = width as u32;
+                bar.mode_height = height as u32;
+
+                if proxy.version() < 2 {
+                    self.send_output_event(bar);
+                }
+            }
+        }
+    }
+
+    fn done(&mut self, _evqh: &mut wayland_client::EventQueueHandle, proxy: &wl_output::WlOutput) {
+        if let Ok(mut bars) = self.bars.lock() {
+            if let Some(bar) = bars.iter_mut().find(|bar| bar.output.equals(proxy)) {
+                self.send_output_event(bar);
+            }
+        }
+    }
+
+    // There is no `wl_surface.preferred_buffer_scale` before version 6 of `wl_surface`,
+    // so the scale of the output the bar has been created on is used
+    fn scale(&mut self,
+             _evqh: &mut wayland_client::EventQueueHandle,
+             proxy: &wl_output::WlOutput,
+             factor: i32) {
+        if let Ok(mut bars) = self.bars.lock() {
+            if let Some(bar) = bars.iter_mut().find(|bar| bar.output.equals(proxy)) {
+                bar.scale = cmp::max(factor, 1) as u32;
+            }
+        }
     }
 }
 declare_handler!(EventHandler, wl_output::Handler, wl_output::WlOutput);
 
+impl zxdg_output_v1::Handler for EventHandler {
+    fn logical_size(&mut self,
+                    _evqh: &mut wayland_client::EventQueueHandle,
+                    proxy: &zxdg_output_v1::ZxdgOutputV1,
+                    width: i32,
+                    height: i32) {
+        if let Ok(mut bars) = self.bars.lock() {
+            if let Some(bar) = bars.iter_mut().find(|bar| is_xdg_output(bar, proxy)) {
+                bar.logical_width = cmp::max(width, 0) as u32;
+                bar.logical_height = cmp::max(height, 0) as u32;
+            }
+        }
+    }
+
+    fn logical_position(&mut self,
+                        _evqh: &mut wayland_client::EventQueueHandle,
+                        proxy: &zxdg_output_v1::ZxdgOutputV1,
+                        x: i32,
+                        y: i32) {
+        if let Ok(mut bars) = self.bars.lock() {
+            if let Some(bar) = bars.iter_mut().find(|bar| is_xdg_output(bar, proxy)) {
+                bar.logical_x = x;
+                bar.logical_y = y;
+            }
+        }
+    }
+
+    fn name(&mut self,
+            _evqh: &mut wayland_client::EventQueueHandle,
+            proxy: &zxdg_output_v1::ZxdgOutputV1,
+            name: String) {
+        if let Ok(mut bars) = self.bars.lock() {
+            if let Some(bar) = bars.iter_mut().find(|bar| is_xdg_output(bar, proxy)) {
+                bar.connector_name = Some(name);
+            }
+        }
+    }
+
+    fn done(&mut self,
+            _evqh: &mut wayland_client::EventQueueHandle,
+            proxy: &zxdg_output_v1::ZxdgOutputV1) {
+        if let Ok(mut bars) = self.bars.lock() {
+            if let Some(bar) = bars.iter_mut().find(|bar| is_xdg_output(bar, proxy)) {
+                self.send_output_event(bar);
+            }
+        }
+    }
+}
+declare_handler!(EventHandler, zxdg_output_v1::Handler, zxdg_output_v1::ZxdgOutputV1);
+
+// Notifies the bar creator once the compositor is ready for the next frame of a bar
+struct FrameHandler {
+    frame_out: mpsc::Sender<u32>,
+    pending: Vec<(u32, wl_callback::WlCallback)>,
+}
+
+impl wl_callback::Handler for FrameHandler {
+    fn done(&mut self,
+            _evqh: &mut wayland_client::EventQueueHandle,
+            proxy: &wl_callback::WlCallback,
+            _callback_data: u32) {
+        if let Some(index) = self.pending.iter().position(|&(_, ref frame)| frame.equals(proxy)) {
+            let (output, callback) = self.pending.remove(index);
+            destroy_callback(&callback);
+            let _ = self.frame_out.send(output);
+        }
+    }
+}
+declare_handler!(FrameHandler, wl_callback::Handler, wl_callback::WlCallback);
+
 pub fn wayland_server_available() -> bool {
     !wayland_client::default_connect().is_err()
 }
 
-pub fn start_wayland_panel(bar_img_in: mpsc::Receiver<(fs::File, i32)>,
-                           resize_out: mpsc::Sender<u32>,
-                           mouse_out: mpsc::Sender<mouse::MouseEvent>)
+pub fn start_wayland_panel(config: Config,
+                           bar_img_in: mpsc::Receiver<BarImage>,
+                           resize_out: mpsc::Sender<OutputEvent>,
+                           mouse_out: mpsc::Sender<(u32, mouse::MouseEvent)>,
+                           frame_out: mpsc::Sender<u32>)
                            -> Result<(), Box<error::Error>> {
     let (display, mut event_queue) = match wayland_client::default_connect() {
         Ok(ret) => ret,
@@ -179,61 +744,197 @@ pub fn start_wayland_panel(bar_img_in: mpsc::Receiver<(fs::File, i32)>,
     event_queue.register::<_, wayland_client::EnvHandler<WaylandEnv>>(&registry, 0);
     event_queue.sync_roundtrip()?;
 
-    let (shell_surface, pointer, surface, output, cursor_surface, cursor_theme) = {
+    let bars = sync::Arc::new(sync::Mutex::new(Vec::new()));
+
+    let (handler, pointer, shm) = {
         let state = event_queue.state();
         let env = state.get_handler::<wayland_client::EnvHandler<WaylandEnv>>(0);
 
-        let surface = request_result_to_result(env.compositor.create_surface(),
-                                               "Compositor already destroyed.")?;
-        let shell_surface = request_result_to_result(env.shell.get_shell_surface(&surface),
-                                                     "Surface already destroyed.")?;
         let pointer = request_result_to_result(env.seat.get_pointer(), "Seat already destroyed.")?;
-        shell_surface.set_toplevel();
-
-        // Make DesktopShell surface a bar
-        env.desktop_shell.set_panel(&env.output, &surface);
 
         // Create a surface for the cursor
         let cursor_surface = request_result_to_result(env.compositor.create_surface(),
                                                       "Compositor already destroyed.")?;
         let cursor_theme = load_cursor_theme(&env.shm);
 
-        // Export output for registering an event handler later
-        let output: wl_output::WlOutput = reexport(env, &registry, "wl_output")?;
+        // Bind all outputs which are already available, later ones are added by the handler
+        let mut new_outputs = Vec::new();
+        for &(name, ref interface, version) in env.globals() {
+            if interface == "wl_output" {
+                let version = cmp::min(version, wl_output::WlOutput::supported_version());
+                let output = request_result_to_result(registry.bind(version, name),
+                                                      "Registry already destroyed.")?;
+                new_outputs.push((name, output));
+            }
+        }
 
-        (shell_surface, pointer, surface, output, cursor_surface, cursor_theme)
-    };
+        // Fall back to desktop_shell for compositors without layer shell support
+        let panel_shell = match reexport(env, &registry, "zwlr_layer_shell_v1") {
+            Ok(layer_shell) => PanelShell::LayerShell(layer_shell),
+            Err(_) => {
+                let desktop_shell: desktop_shell::DesktopShell =
+                    reexport(env, &registry, "desktop_shell").map_err(|_| {
+                        "Compositor supports neither zwlr_layer_shell_v1 nor desktop_shell."
+                    })?;
 
-    event_queue.add_handler(EventHandler::new(resize_out, mouse_out, cursor_theme, cursor_surface)?);
+                // Positioning the panel has only been added in version 3
+                // Older versions always put it at the top, which is only a problem for bottom bars
+                if desktop_shell.version() >= 3 {
+                    desktop_shell.set_panel_position(panel_position(config.top).to_raw());
+                } else if !config.top {
+                    return Err(format!("Compositor only supports desktop_shell version {}, but \
+                                        version 3 is required for a bar at the bottom.",
+                                       desktop_shell.version())
+                        .into());
+                }
 
-    event_queue.register::<_, EventHandler>(&shell_surface, 1);
-    event_queue.register::<_, EventHandler>(&pointer, 1);
-    event_queue.register::<_, EventHandler>(&output, 1);
+                PanelShell::DesktopShell(desktop_shell, reexport(env, &registry, "wl_shell")?)
+            }
+        };
 
-    {
-        let state = event_queue.state();
-        let env = state.get_handler::<wayland_client::EnvHandler<WaylandEnv>>(0);
+        // Export globals required for creating new bars at runtime
+        let handler = EventHandler {
+            handler_id: 0,
+            bars: bars.clone(),
+            new_outputs: new_outputs,
+            compositor: reexport(env, &registry, "wl_compositor")?,
+            panel_shell: panel_shell,
+            xdg_output_manager: reexport(env, &registry, "zxdg_output_manager_v1").ok(),
+            bar_height: config.bar_height,
+            top: config.top,
+            resize_out: resize_out,
+            mouse_out: mouse_out,
+            cursor_theme: cursor_theme,
+            cursor_surface: cursor_surface,
+            pointer_output: None,
+            pointer_origin: (0f64, 0f64),
+            last_x: 0f64,
+            last_y: 0f64,
+            scroll_distance: [0f64; 2],
+            discrete_axes: [false; 2],
+        };
         let shm: wl_shm::WlShm = reexport(env, &registry, "wl_shm")?;
 
-        let mut wlc_unbugged = false;
-        thread::spawn(move || {
-            while let Ok((bar_img, bar_height)) = bar_img_in.recv() {
-                let output_width = match bar_img.metadata() {
-                    Ok(meta) => meta.len() as i32 / bar_height / 4,
-                    _ => 0,
+        (handler, pointer, shm)
+    };
+
+    let handler_id = event_queue.add_handler_with_init(handler);
+    event_queue.register::<_, EventHandler>(&pointer, handler_id);
+
+    // Outputs can be added and removed at runtime, so the registry is handled manually now
+    event_queue.register::<_, EventHandler>(&registry, handler_id);
+
+    // Images are forwarded to the drawing thread with a byte written to a pipe, so it can wait
+    // for new images and Wayland events at the same time
+    let (wake_in, mut wake_out) = create_wake_pipe()?;
+    let (image_out, image_in) = mpsc::channel();
+    thread::spawn(move || {
+        for bar_img in bar_img_in {
+            if image_out.send(bar_img).is_err() {
+                break;
+            }
+
+            // A full pipe already wakes up the drawing thread
+            let _ = wake_out.write(&[0]);
+        }
+    });
+
+    thread::spawn(move || {
+        // Buffer releases and frame callbacks are dispatched on a separate queue owned by the
+        // drawing thread
+        let mut wake_in = wake_in;
+        let mut event_queue = display.create_event_queue();
+        let buffer_handler_id = event_queue.add_handler(shm::BufferHandler);
+        let frame_handler_id = event_queue.add_handler(FrameHandler {
+            frame_out: frame_out.clone(),
+            pending: Vec::new(),
+        });
+        let mut pools = collections::HashMap::new();
+
+        loop {
+            // Sleep until there is either a new image or an event for this queue
+            let message = match image_in.try_recv() {
+                Ok(message) => Some(message),
+                Err(mpsc::TryRecvError::Disconnected) => break,
+                Err(mpsc::TryRecvError::Empty) => {
+                    if wait_for_events(&display, &mut event_queue, &mut wake_in).is_err() {
+                        break;
+                    }
+                    None
+                }
+            };
+
+            if event_queue.dispatch_pending().is_err() {
+                break;
+            }
+
+            let bar_img = match message {
+                Some(bar_img) => bar_img,
+                None => continue,
+            };
+            let output = bar_img.output;
+            let output_width = bar_img.bytes.len() as i32 / bar_img.height / 4;
+
+            let mut bars = match bars.lock() {
+                Ok(bars) => bars,
+                Err(_) => break,
+            };
+
+            // Pools and frame callbacks of removed bars are not required anymore
+            pools.retain(|id, _| bars.iter().any(|bar| bar.id == *id));
+            event_queue.state()
+                .get_mut_handler::<FrameHandler>(frame_handler_id)
+                .pending
+                .retain(|&(id, ref callback)| {
+                    let alive = bars.iter().any(|bar| bar.id == id);
+                    if !alive {
+                        destroy_callback(callback);
+                    }
+                    alive
+                });
+
+            let mut frame_requested = false;
+            if let Some(bar) = bars.iter_mut().find(|bar| bar.id == output) {
+                if output_width > 0 && !pools.contains_key(&output) {
+                    if let Ok(pool) = shm::ShmPool::new(&shm, buffer_handler_id) {
+                        pools.insert(output, pool);
+                    }
+                }
+
+                let callback = match pools.get_mut(&output) {
+                    Some(pool) if output_width > 0 => {
+                        draw_bar(&bar_img,
+                                 pool,
+                                 &mut event_queue,
+                                 frame_handler_id,
+                                 &bar.surface,
+                                 &display,
+                                 output_width)
+                            .ok()
+                    }
+                    _ => None,
                 };
 
-                if output_width > 0 {
-                    let _ = draw_bar(&bar_img, &shm, &surface, &display, output_width, bar_height);
+                if let Some(callback) = callback {
+                    event_queue.state()
+                        .get_mut_handler::<FrameHandler>(frame_handler_id)
+                        .pending
+                        .push((output, callback));
+                    frame_requested = true;
                 }
 
-                if !wlc_unbugged {
-                    surface.commit();
-                    wlc_unbugged = true;
+                if !bar.committed {
+                    bar.surface.commit();
+                    bar.committed = true;
                 }
             }
-        });
-    }
+
+            // The bar creator waits for the frame, so it has to be notified if there is none
+            if !frame_requested && frame_out.send(output).is_err() {
+                break;
+            }
+        }
+    });
 
     // Dispatch all Wayland Events until the end of Dawn
     loop {
@@ -241,36 +942,201 @@ pub fn start_wayland_panel(bar_img_in: mpsc::Receiver<(fs::File, i32)>,
     }
 }
 
-fn draw_bar(bar_img: &fs::File,
-            shm: &wl_shm::WlShm,
+// Returns the callback which is notified once the compositor is ready for the next frame
+fn draw_bar(bar_img: &BarImage,
+            pool: &mut shm::ShmPool,
+            evqh: &mut wayland_client::EventQueueHandle,
+            frame_handler_id: usize,
             surface: &wl_surface::WlSurface,
             display: &wl_display::WlDisplay,
-            bar_width: i32,
-            bar_height: i32)
-            -> Result<(), Box<error::Error>> {
-    let pool = match shm.create_pool(bar_img.as_raw_fd(), bar_height * bar_width * 4) {
-        wayland_client::RequestResult::Sent(pool) => pool,
-        wayland_client::RequestResult::Destroyed => Err("SHM already destroyed.".to_owned())?,
-    };
+            bar_width: i32)
+            -> Result<wl_callback::WlCallback, Box<error::Error>> {
+    let (bar_height, scale) = (bar_img.height, bar_img.scale);
+    let buffer = pool.draw(evqh, &bar_img.bytes, bar_width, bar_height)?;
 
-    let buffer = match pool.create_buffer(0,
-                                          bar_width,
-                                          bar_height,
-                                          bar_width * 4,
-                                          wl_shm::Format::Argb8888) {
-        wayland_client::RequestResult::Sent(pool) => pool,
-        wayland_client::RequestResult::Destroyed => Err("Pool already destroyed.".to_owned())?,
-    };
+    // Registered before committing, so `done` can't be received before the handler is set
+    let callback = request_result_to_result(surface.frame(), "Surface already destroyed.")?;
+    evqh.register::<_, FrameHandler>(&callback, frame_handler_id);
+
+    // Buffer scale has only been added in version 3 of `wl_surface`
+    if surface.version() >= 3 {
+        surface.set_buffer_scale(scale);
+    }
+
+    surface.attach(Some(buffer), 0, 0);
+
+    // Damage in buffer coordinates has only been added in version 4 of `wl_surface`
+    for &(x, width) in &bar_img.damage {
+        if surface.version() >= 4 {
+            surface.damage_buffer(x, 0, width, bar_height);
+        } else {
+            let surface_x = x / scale;
+            let surface_width = (x + width + scale - 1) / scale - surface_x;
+            surface.damage(surface_x, 0, surface_width, bar_height / scale);
+        }
+    }
 
-    surface.attach(Some(&buffer), 0, 0);
     surface.commit();
 
     // Ignore if writing to display failed to try again next time
     let _ = display.flush();
 
+    Ok(callback)
+}
+
+// `wl_callback.done` is no destructor in the protocol version used by wayland-client and
+// proxies are never freed on drop, so it is destroyed the same way the generated destructors do
+fn destroy_callback(callback: &wl_callback::WlCallback) {
+    use std::os::raw::c_void;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use wayland_sys::client::*;
+
+    unsafe {
+        let data = ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_proxy_get_user_data, callback.ptr());
+        let data: Box<(*mut c_void, sync::Arc<AtomicBool>)> = Box::from_raw(data as *mut _);
+        data.1.store(false, Ordering::SeqCst);
+        ffi_dispatch!(WAYLAND_CLIENT_HANDLE, wl_proxy_destroy, callback.ptr());
+    }
+}
+
+// Blocks until events for the queue have been read or the wake pipe has been written to
+// Events can also be read by the main thread, which is handled by `prepare_read`
+fn wait_for_events(display: &wl_display::WlDisplay,
+                   event_queue: &mut wayland_client::EventQueue,
+                   wake_in: &mut fs::File)
+                   -> Result<(), io::Error> {
+    let guard = loop {
+        match event_queue.prepare_read() {
+            Some(guard) => break guard,
+            None => {
+                event_queue.dispatch_pending()?;
+            }
+        }
+    };
+
+    // Requests have to be sent before waiting for their events
+    let _ = display.flush();
+
+    let mut fds = [libc::pollfd {
+                       fd: display.get_fd(),
+                       events: libc::POLLIN,
+                       revents: 0,
+                   },
+                   libc::pollfd {
+                       fd: wake_in.as_raw_fd(),
+                       events: libc::POLLIN,
+                       revents: 0,
+                   }];
+    let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+
+    if fds[0].revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+        guard.cancel();
+        return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Wayland connection lost."));
+    } else if result > 0 && fds[0].revents & libc::POLLIN != 0 {
+        guard.read_events()?;
+    } else {
+        guard.cancel();
+    }
+
+    // The pipe is non-blocking, so this stops once it is empty
+    let mut buf = [0; 64];
+    while let Ok(read) = wake_in.read(&mut buf) {
+        if read == 0 {
+            break;
+        }
+    }
+
     Ok(())
 }
 
+// Returns the reading and writing end of a non-blocking pipe
+fn create_wake_pipe() -> Result<(fs::File, fs::File), io::Error> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) })
+}
+
+// Bars are always horizontal, so `Left` and `Right` are never used
+fn axis_index(axis: wl_pointer::Axis) -> usize {
+    match axis {
+        wl_pointer::Axis::VerticalScroll => 0,
+        wl_pointer::Axis::HorizontalScroll => 1,
+    }
+}
+
+// Positive values scroll down or to the right
+fn scroll_direction(axis: wl_pointer::Axis, value: f64) -> mouse::ScrollDirection {
+    match (axis, value > 0f64) {
+        (wl_pointer::Axis::VerticalScroll, true) => mouse::ScrollDirection::DOWN,
+        (wl_pointer::Axis::VerticalScroll, false) => mouse::ScrollDirection::UP,
+        (wl_pointer::Axis::HorizontalScroll, true) => mouse::ScrollDirection::RIGHT,
+        (wl_pointer::Axis::HorizontalScroll, false) => mouse::ScrollDirection::LEFT,
+    }
+}
+
+fn panel_position(top: bool) -> desktop_shell::PanelPosition {
+    if top {
+        desktop_shell::PanelPosition::Top
+    } else {
+        desktop_shell::PanelPosition::Bottom
+    }
+}
+
+// The logical size from xdg-output already includes transform and scale
+fn logical_width(bar: &Bar) -> u32 {
+    if bar.logical_width > 0 {
+        return bar.logical_width;
+    }
+
+    // Width and height of the mode are swapped on rotated outputs
+    let width = match bar.transform {
+        wl_output::Transform::_90 |
+        wl_output::Transform::_270 |
+        wl_output::Transform::Flipped90 |
+        wl_output::Transform::Flipped270 => bar.mode_height,
+        _ => bar.mode_width,
+    };
+
+    width / bar.scale
+}
+
+// Top left corner of the bar in the global space, used for absolute pointer positions
+// Without xdg-output every output is assumed to start at the origin
+fn bar_origin(bar: &Bar, bar_height: u32, top: bool) -> (f64, f64) {
+    let y = if top {
+        bar.logical_y
+    } else {
+        let output_height = match (bar.logical_height, &bar.transform) {
+            (0, &wl_output::Transform::_90) |
+            (0, &wl_output::Transform::_270) |
+            (0, &wl_output::Transform::Flipped90) |
+            (0, &wl_output::Transform::Flipped270) => bar.mode_width / bar.scale,
+            (0, _) => bar.mode_height / bar.scale,
+            (logical_height, _) => logical_height,
+        };
+        bar.logical_y + output_height as i32 - bar_height as i32
+    };
+
+    (bar.logical_x as f64, y as f64)
+}
+
+fn is_xdg_output(bar: &Bar, xdg_output: &zxdg_output_v1::ZxdgOutputV1) -> bool {
+    match bar.xdg_output {
+        Some(ref bar_xdg_output) => bar_xdg_output.equals(xdg_output),
+        None => false,
+    }
+}
+
+fn is_layer_surface(bar: &Bar, layer_surface: &zwlr_layer_surface_v1::ZwlrLayerSurfaceV1) -> bool {
+    match bar.layer_surface {
+        Some(ref bar_layer_surface) => bar_layer_surface.equals(layer_surface),
+        None => false,
+    }
+}
+
 fn load_cursor_theme(shm: &wl_shm::WlShm) -> wayland_client::cursor::CursorTheme {
     let name = env::var("SWAY_CURSOR_THEME").unwrap_or_else(|_| String::from("default"));
     let size = env::var("SWAY_CURSOR_SIZE").unwrap_or_else(|_| String::from("16"));
@@ -284,8 +1150,9 @@ fn reexport<T: wayland_client::Proxy>(env: &wayland_client::EnvHandler<WaylandEn
                                       -> Result<T, Box<error::Error>> {
     for &(name, ref interface, version) in env.globals() {
         if interface == interface_name {
+            let version = cmp::min(version, T::supported_version());
             return Ok(request_result_to_result(registry.bind::<T>(version, name),
-                                               "Unabled to find WlOutput in globals.")?);
+                                               "Registry already destroyed.")?);
         }
     }
     Err(format!("Unable to find {} in globals.", interface_name))?
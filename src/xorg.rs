@@ -1,50 +1,537 @@
 use xcb;
-use std::fs;
+use std::cmp;
+use std::time;
 use std::error;
-use std::sync::mpsc;
+use std::thread;
+use std::sync::{self, mpsc};
 use xcb_util::{icccm, ewmh};
 
-use mouse::MouseEvent;
+use mouse::{self, MouseEvent};
+use output::{OutputEvent, BarImage};
+use parse_input::Config;
 
-pub fn start_xorg_panel(bar_img_in: mpsc::Receiver<(fs::File, i32)>,
-                        resize_out: mpsc::Sender<u32>,
-                        mouse_out: mpsc::Sender<MouseEvent>)
+// X11 has no frame callbacks, so the next frame is requested after a fixed delay instead
+const FRAME_INTERVAL_MS: u64 = 16;
+
+// Properties of the X screen all bars are created on
+struct ScreenInfo {
+    root: xcb::Window,
+    visual: xcb::Visualid,
+    black_pixel: u32,
+    depth: u8,
+    width: u16,
+    height: u16,
+}
+
+// Geometry of a single monitor, identified by its RandR CRTC
+#[derive(Clone, PartialEq)]
+struct Monitor {
+    id: u32,
+    x: i16,
+    y: i16,
+    width: u16,
+    height: u16,
+    names: Vec<String>,
+}
+
+struct Bar {
+    monitor: Monitor,
+    wid: xcb::Window,
+    last_img: Option<(Vec<u8>, i32)>,
+}
+
+pub fn start_xorg_panel(config: Config,
+                        bar_img_in: mpsc::Receiver<BarImage>,
+                        resize_out: mpsc::Sender<OutputEvent>,
+                        mouse_out: mpsc::Sender<(u32, MouseEvent)>,
+                        frame_out: mpsc::Sender<u32>)
                         -> Result<(), Box<error::Error>> {
     let (connection, screen) =
         xcb::Connection::connect(None).map_err(|_| "X Connection error::Error.")?;
     let connection =
         ewmh::Connection::connect(connection).map_err(|_| "X Connection error::Error.")?;
-    let screen = connection.get_setup().roots().nth(screen as usize).ok_or("No screen found.")?;
+    let connection = sync::Arc::new(connection);
+
+    let mut screen = {
+        let screen =
+            connection.get_setup().roots().nth(screen as usize).ok_or("No screen found.")?;
+        ScreenInfo {
+            root: screen.root(),
+            visual: screen.root_visual(),
+            black_pixel: screen.black_pixel(),
+            depth: screen.root_depth(),
+            width: screen.width_in_pixels(),
+            height: screen.height_in_pixels(),
+        }
+    };
+
+    // All bars share the root depth, so a single graphics context is enough
+    let gc = connection.generate_id();
+    xcb::create_gc(&connection, gc, screen.root, &[]);
+
+    // Get notified when the screen geometry changes
+    xcb::change_window_attributes(&connection,
+                                  screen.root,
+                                  &[(xcb::CW_EVENT_MASK, xcb::EVENT_MASK_STRUCTURE_NOTIFY)]);
+
+    // Get notified when monitors are added, removed or moved
+    let randr_base = match connection.get_extension_data(&mut xcb::randr::id()) {
+        Some(ref data) if data.present() => {
+            xcb::randr::select_input(&connection,
+                                     screen.root,
+                                     (xcb::randr::NOTIFY_MASK_SCREEN_CHANGE |
+                                      xcb::randr::NOTIFY_MASK_CRTC_CHANGE) as u16);
+            Some(data.first_event())
+        }
+        _ => None,
+    };
+
+    let bars = sync::Arc::new(sync::Mutex::new(Vec::new()));
+    {
+        let mut bars = bars.lock().map_err(|e| e.to_string())?;
+        update_bars(&connection, &config, &screen, &mut bars, &resize_out);
+    }
+
+    {
+        let connection = connection.clone();
+        let bars = bars.clone();
+        let depth = screen.depth;
+        thread::spawn(move || {
+            // The whole bar is redrawn, so damage is ignored
+            while let Ok(bar_img) = bar_img_in.recv() {
+                let output = bar_img.output;
+                let (bytes, bar_height) = (bar_img.bytes, bar_img.height);
+                {
+                    let mut bars = match bars.lock() {
+                        Ok(bars) => bars,
+                        Err(_) => break,
+                    };
+
+                    if let Some(bar) = bars.iter_mut().find(|bar| bar.monitor.id == output) {
+                        draw_bar(&connection, bar.wid, gc, depth, &bytes, bar_height);
+
+                        // Last image that has been drawn, required for redrawing on expose
+                        bar.last_img = Some((bytes, bar_height));
+                    }
+                }
+
+                thread::sleep(time::Duration::from_millis(FRAME_INTERVAL_MS));
+                if frame_out.send(output).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Dispatch all X Events until the connection is closed
+    let (mut last_x, mut last_y) = (0f64, 0f64);
+    let (mut root_x, mut root_y) = (0f64, 0f64);
+    while let Some(event) = connection.wait_for_event() {
+        let response_type = event.response_type() & !0x80;
+        match response_type {
+            xcb::EXPOSE => {
+                let event: &xcb::ExposeEvent = xcb::cast_event(&event);
+                let bars = bars.lock().map_err(|e| e.to_string())?;
+                if let Some(bar) = bars.iter().find(|bar| bar.wid == event.window()) {
+                    if let Some((ref bytes, bar_height)) = bar.last_img {
+                        draw_bar(&connection, bar.wid, gc, screen.depth, bytes, bar_height);
+                    }
+                }
+            }
+            xcb::BUTTON_PRESS | xcb::BUTTON_RELEASE => {
+                let event: &xcb::ButtonPressEvent = xcb::cast_event(&event);
+                let output = match bar_at_window(&bars, event.event())? {
+                    Some(output) => output,
+                    None => continue,
+                };
+
+                last_x = event.event_x() as f64;
+                last_y = event.event_y() as f64;
+                root_x = event.root_x() as f64;
+                root_y = event.root_y() as f64;
+
+                // X11 reports every scroll step as a press and release of buttons 4 to 7
+                let scroll = match event.detail() {
+                    4 => Some(mouse::ScrollDirection::UP),
+                    5 => Some(mouse::ScrollDirection::DOWN),
+                    6 => Some(mouse::ScrollDirection::LEFT),
+                    7 => Some(mouse::ScrollDirection::RIGHT),
+                    _ => None,
+                };
+                if scroll.is_some() {
+                    if response_type == xcb::BUTTON_PRESS {
+                        let _ = mouse_out.send((output,
+                                                MouseEvent {
+                                                    button: None,
+                                                    state: None,
+                                                    scroll: scroll,
+                                                    x: last_x,
+                                                    y: last_y,
+                                                    root_x: root_x,
+                                                    root_y: root_y,
+                                                    scale: 1,
+                                                }));
+                    }
+                    continue;
+                }
+
+                let button = match event.detail() {
+                    1 => mouse::BTN_LEFT,
+                    2 => mouse::BTN_MIDDLE,
+                    3 => mouse::BTN_RIGHT,
+                    // Additional buttons follow the middle button in Linux input event codes
+                    button if button >= 8 => mouse::BTN_MIDDLE + button as u32 - 7,
+                    _ => continue,
+                };
+                let state = if response_type == xcb::BUTTON_PRESS {
+                    mouse::ButtonState::PRESSED
+                } else {
+                    mouse::ButtonState::RELEASED
+                };
+
+                let _ = mouse_out.send((output,
+                                        MouseEvent {
+                                            button: Some(button),
+                                            state: Some(state),
+                                            scroll: None,
+                                            x: last_x,
+                                            y: last_y,
+                                            root_x: root_x,
+                                            root_y: root_y,
+                                            scale: 1,
+                                        }));
+            }
+            xcb::MOTION_NOTIFY => {
+                let event: &xcb::MotionNotifyEvent = xcb::cast_event(&event);
+                if event.event_x() as f64 == last_x && event.event_y() as f64 == last_y {
+                    continue;
+                }
+                let output = match bar_at_window(&bars, event.event())? {
+                    Some(output) => output,
+                    None => continue,
+                };
+
+                last_x = event.event_x() as f64;
+                last_y = event.event_y() as f64;
+                root_x = event.root_x() as f64;
+                root_y = event.root_y() as f64;
+                let _ = mouse_out.send((output,
+                                        MouseEvent {
+                                            button: None,
+                                            state: None,
+                                            scroll: None,
+                                            x: last_x,
+                                            y: last_y,
+                                            root_x: root_x,
+                                            root_y: root_y,
+                                            scale: 1,
+                                        }));
+            }
+            xcb::LEAVE_NOTIFY => {
+                let event: &xcb::LeaveNotifyEvent = xcb::cast_event(&event);
+                let output = match bar_at_window(&bars, event.event())? {
+                    Some(output) => output,
+                    None => continue,
+                };
+
+                last_x = -1f64;
+                last_y = -1f64;
+                let _ = mouse_out.send((output,
+                                        MouseEvent {
+                                            button: None,
+                                            state: None,
+                                            scroll: None,
+                                            x: -1f64,
+                                            y: -1f64,
+                                            root_x: -1f64,
+                                            root_y: -1f64,
+                                            scale: 1,
+                                        }));
+            }
+            xcb::CONFIGURE_NOTIFY => {
+                let event: &xcb::ConfigureNotifyEvent = xcb::cast_event(&event);
+                if event.window() != screen.root ||
+                   (event.width() == screen.width && event.height() == screen.height) {
+                    continue;
+                }
+
+                screen.width = event.width();
+                screen.height = event.height();
+
+                let mut bars = bars.lock().map_err(|e| e.to_string())?;
+                update_bars(&connection, &config, &screen, &mut bars, &resize_out);
+            }
+            _ if randr_base.map_or(false, |base| {
+                response_type == base + xcb::randr::SCREEN_CHANGE_NOTIFY ||
+                response_type == base + xcb::randr::NOTIFY
+            }) => {
+                let mut bars = bars.lock().map_err(|e| e.to_string())?;
+                update_bars(&connection, &config, &screen, &mut bars, &resize_out);
+            }
+            _ => (),
+        }
+    }
+
+    Err("X Connection closed.".to_owned())?
+}
+
+// Returns the output of the bar that belongs to a window
+fn bar_at_window(bars: &sync::Mutex<Vec<Bar>>,
+                 wid: xcb::Window)
+                 -> Result<Option<u32>, Box<error::Error>> {
+    let bars = bars.lock().map_err(|e| e.to_string())?;
+    Ok(bars.iter().find(|bar| bar.wid == wid).map(|bar| bar.monitor.id))
+}
+
+// Synchronizes the bars with the currently active monitors
+// Every bar which has been created, removed or resized is reported on `resize_out`
+fn update_bars(connection: &ewmh::Connection,
+               config: &Config,
+               screen: &ScreenInfo,
+               bars: &mut Vec<Bar>,
+               resize_out: &mpsc::Sender<OutputEvent>) {
+    let monitors = get_monitors(connection, screen);
+
+    // Remove bars of monitors which have been disconnected
+    bars.retain(|bar| {
+        if monitors.iter().any(|monitor| monitor.id == bar.monitor.id) {
+            true
+        } else {
+            xcb::destroy_window(connection, bar.wid);
+            let _ = resize_out.send(OutputEvent {
+                id: bar.monitor.id,
+                width: 0,
+                scale: 1,
+                names: Vec::new(),
+            });
+            false
+        }
+    });
+
+    for monitor in monitors {
+        let output_event = OutputEvent {
+            id: monitor.id,
+            width: monitor.width as u32,
+            scale: 1,
+            names: monitor.names.clone(),
+        };
 
-    let (w, h) = (100, 100);
+        if let Some(bar) = bars.iter_mut().find(|bar| bar.monitor.id == monitor.id) {
+            if bar.monitor != monitor {
+                move_bar(connection, bar.wid, config, &monitor);
+                bar.monitor = monitor;
+                let _ = resize_out.send(output_event);
+            }
+            continue;
+        }
 
-    let x = 0;
-    let y = 0;
+        let wid = create_window(connection, config, screen, &monitor);
+        bars.push(Bar {
+            monitor: monitor,
+            wid: wid,
+            last_img: None,
+        });
+        let _ = resize_out.send(output_event);
+    }
+
+    // Struts depend on the screen size, so they are updated for every bar
+    for bar in bars.iter() {
+        set_struts(connection, bar.wid, config, screen, &bar.monitor);
+    }
+
+    connection.flush();
+}
+
+// Queries all active CRTCs from RandR
+// Falls back to the whole screen if RandR is not available
+fn get_monitors(connection: &ewmh::Connection, screen: &ScreenInfo) -> Vec<Monitor> {
+    let mut monitors: Vec<Monitor> = Vec::new();
+
+    let cookie = xcb::randr::get_screen_resources_current(connection, screen.root);
+    if let Ok(resources) = cookie.get_reply() {
+        for crtc in resources.crtcs() {
+            let cookie = xcb::randr::get_crtc_info(connection,
+                                                   *crtc,
+                                                   resources.config_timestamp());
+            let info = match cookie.get_reply() {
+                Ok(info) => info,
+                Err(_) => continue,
+            };
+
+            // Skip disabled CRTCs and mirrored monitors
+            if info.width() == 0 || info.height() == 0 ||
+               monitors.iter().any(|m| m.x == info.x() && m.y == info.y()) {
+                continue;
+            }
+
+            // Names of all outputs connected to this CRTC, like `DP-1`
+            let names = info.outputs()
+                .iter()
+                .filter_map(|output| {
+                    xcb::randr::get_output_info(connection, *output, resources.config_timestamp())
+                        .get_reply()
+                        .ok()
+                })
+                .map(|output_info| String::from_utf8_lossy(output_info.name()).into_owned())
+                .collect();
+
+            monitors.push(Monitor {
+                id: *crtc,
+                x: info.x(),
+                y: info.y(),
+                width: info.width(),
+                height: info.height(),
+                names: names,
+            });
+        }
+    }
+
+    // CRTC ids are never 0, so this can't clash with a RandR monitor
+    if monitors.is_empty() {
+        monitors.push(Monitor {
+            id: 0,
+            x: 0,
+            y: 0,
+            width: screen.width,
+            height: screen.height,
+            names: Vec::new(),
+        });
+    }
+
+    monitors
+}
+
+fn create_window(connection: &ewmh::Connection,
+                 config: &Config,
+                 screen: &ScreenInfo,
+                 monitor: &Monitor)
+                 -> xcb::Window {
+    let (x, y) = bar_position(config, monitor);
 
     let wid = connection.generate_id();
-    xcb::create_window(&connection,
+    xcb::create_window(connection,
                        xcb::COPY_FROM_PARENT as u8,
                        wid,
-                       screen.root(),
+                       screen.root,
                        x,
                        y,
-                       w,
-                       h,
-                       10, // border_width
+                       monitor.width,
+                       config.bar_height as u16,
+                       0, // border_width
                        xcb::WINDOW_CLASS_INPUT_OUTPUT as u16,
-                       screen.root_visual(),
-                       &[(xcb::CW_BACKING_PIXEL, screen.black_pixel())]);
+                       screen.visual,
+                       &[(xcb::CW_BACK_PIXEL, screen.black_pixel),
+                         (xcb::CW_EVENT_MASK,
+                          xcb::EVENT_MASK_EXPOSURE | xcb::EVENT_MASK_BUTTON_PRESS |
+                          xcb::EVENT_MASK_BUTTON_RELEASE |
+                          xcb::EVENT_MASK_POINTER_MOTION |
+                          xcb::EVENT_MASK_LEAVE_WINDOW)]);
 
-    icccm::set_wm_class(&connection, wid, "leechbar", "Bar");
-    icccm::set_wm_name(&connection, wid, "leechbar");
-    ewmh::set_wm_name(&connection, wid, "leechbar");
-    ewmh::set_wm_state(&connection,
+    icccm::set_wm_class(connection, wid, "leechbar", "Bar");
+    icccm::set_wm_name(connection, wid, "leechbar");
+    ewmh::set_wm_name(connection, wid, "leechbar");
+    ewmh::set_wm_state(connection,
                        wid,
                        &[connection.WM_STATE_STICKY(), connection.WM_STATE_ABOVE()]);
-    ewmh::set_wm_window_type(&connection, wid, &[connection.WM_WINDOW_TYPE_DOCK()]);
+    ewmh::set_wm_window_type(connection, wid, &[connection.WM_WINDOW_TYPE_DOCK()]);
 
-    xcb::map_window(&connection, wid);
-    connection.flush();
+    xcb::map_window(connection, wid);
+
+    wid
+}
 
-    loop {}
+// Position of the bar at the top or bottom edge of a monitor
+fn bar_position(config: &Config, monitor: &Monitor) -> (i16, i16) {
+    if config.top {
+        (monitor.x, monitor.y)
+    } else {
+        (monitor.x, monitor.y + monitor.height as i16 - config.bar_height as i16)
+    }
+}
+
+// Places the bar at the top or bottom edge of the monitor, spanning its whole width
+fn move_bar(connection: &ewmh::Connection, wid: xcb::Window, config: &Config, monitor: &Monitor) {
+    let (x, y) = bar_position(config, monitor);
+
+    xcb::configure_window(connection,
+                          wid,
+                          &[(xcb::CONFIG_WINDOW_X as u16, x as u32),
+                            (xcb::CONFIG_WINDOW_Y as u16, y as u32),
+                            (xcb::CONFIG_WINDOW_WIDTH as u16, monitor.width as u32),
+                            (xcb::CONFIG_WINDOW_HEIGHT as u16, config.bar_height)]);
+}
+
+// Reserves space for the bar so maximized windows don't overlap it
+// Struts are relative to the edges of the screen, not the edges of the monitor
+fn set_struts(connection: &ewmh::Connection,
+              wid: xcb::Window,
+              config: &Config,
+              screen: &ScreenInfo,
+              monitor: &Monitor) {
+    let start_x = cmp::max(monitor.x, 0) as u32;
+    let end_x = start_x + cmp::max(monitor.width as u32, 1) - 1;
+
+    let (top, bottom) = if config.top {
+        (cmp::max(monitor.y, 0) as u32 + config.bar_height, 0)
+    } else {
+        let monitor_bottom = cmp::max(monitor.y, 0) as u32 + monitor.height as u32;
+        (0, (screen.height as u32).saturating_sub(monitor_bottom) + config.bar_height)
+    };
+
+    ewmh::set_wm_strut(connection, wid, 0, 0, top, bottom);
+    ewmh::set_wm_strut_partial(connection,
+                               wid,
+                               ewmh::StrutPartial {
+                                   left: 0,
+                                   right: 0,
+                                   top: top,
+                                   bottom: bottom,
+                                   left_start_y: 0,
+                                   left_end_y: 0,
+                                   right_start_y: 0,
+                                   right_end_y: 0,
+                                   top_start_x: if config.top { start_x } else { 0 },
+                                   top_end_x: if config.top { end_x } else { 0 },
+                                   bottom_start_x: if config.top { 0 } else { start_x },
+                                   bottom_end_x: if config.top { 0 } else { end_x },
+                               });
+}
+
+// Puts the BGRA bytes of the bar into the window
+// This is split into multiple requests if the image exceeds the maximum request length
+fn draw_bar(connection: &ewmh::Connection,
+            wid: xcb::Window,
+            gc: xcb::Gcontext,
+            depth: u8,
+            bytes: &[u8],
+            bar_height: i32) {
+    if bar_height <= 0 || bytes.is_empty() {
+        return;
+    }
+
+    let bar_width = bytes.len() as u32 / bar_height as u32 / 4;
+    if bar_width == 0 {
+        return;
+    }
+
+    // Maximum request length is in units of 4 bytes, minus the put_image request header
+    let max_bytes = connection.get_maximum_request_length() as usize * 4 - 24;
+    let rows_per_request = cmp::max(1, max_bytes / (bar_width as usize * 4));
+
+    for (i, rows) in bytes.chunks(rows_per_request * bar_width as usize * 4).enumerate() {
+        let row_count = rows.len() / (bar_width as usize * 4);
+        xcb::put_image(connection,
+                       xcb::IMAGE_FORMAT_Z_PIXMAP as u8,
+                       wid,
+                       gc,
+                       bar_width as u16,
+                       row_count as u16,
+                       0,
+                       (i * rows_per_request) as i16,
+                       0,
+                       depth,
+                       rows);
+    }
+
+    connection.flush();
 }